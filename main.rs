@@ -1,69 +1,939 @@
 /*EBNF GRAMMAR 巴克斯范式
-<Expr> ::= <AddSubExpr>
-<AddSubExpr> ::= <MulDivExpr> {('+' | '-') <MulDivExpr>}
-<MulDivExpr> ::= <PrimaryExpr> {('*' | '/') <PrimaryExpr>}
-<PrimaryExpr> ::= NUM | '-'NUM | '(' <Expr> ')'
+<Line> ::= <Expr> ['=>' IDENT]
+<Expr> ::= <BitXorExpr> {'|>' IDENT}
+<BitXorExpr> ::= <CompareExpr> {'^' <CompareExpr>}
+<CompareExpr> ::= <AddSubExpr> {('<' | '<=' | '>' | '>=' | '==' | '!=') <AddSubExpr>}
+<AddSubExpr> ::= <MulDivExpr> {('+' | '-') <MulDivExpr> ['%']}
+<MulDivExpr> ::= <UnaryExpr> {('*' | '/' | 'div' | 'mod') <UnaryExpr>}
+<UnaryExpr> ::= '-' <UnaryExpr> | <PowExpr>
+<PowExpr> ::= <PrimaryExpr> [SUP] ['**' <UnaryExpr>]
+<PrimaryExpr> ::= NUM | IDENT | '(' <Expr> ')' | '[' <Expr> ']' | '{' <Expr> '}' | <Call>
+<Call> ::= 'if' '(' <Expr> ',' <Expr> ',' <Expr> ')' | 'table' '(' <Expr> ',' IDENT ',' <Expr> ',' <Expr> [',' <Expr>] ')' | IDENT '(' [<Expr> {',' <Expr>}] ')'
+<PrimaryExpr> 还可以是 $N，表示批处理模式下之前第 N 行的结果（从 1 开始）
+<PrimaryExpr> 也可以是 @，表示上一行的结果，等价于 $N 中 N 取上一行的行号；第一行没有上一行结果
+--implicit-ans 下，以二元运算符开头的行把上一行的结果当作左操作数：上一行是 5 时，* 2 == 10，+ 3 == 8；
+开头的 '-' 仍是一元负号，- 3 == -3，要减去 3 请写 @ - 3
+行尾的 '=> 名字' 把这一行的结果保存到变量中，之后各行可以直接用名字引用：3 + 4 => total，下一行 total + 1 == 8
+
+'|>' 把左边的值作为唯一的参数传给右边的函数，优先级最低：255 |> popcount == 8，1 + 1 |> bit_reverse 先算 1 + 1；
+可以连写 x |> f |> g == g(f(x))，只有一个参数的函数才能这样用
+'^' 是按位异或 (和 C 一样，优先级低于加减)，乘方请用 '**'：5 ^ 3 == 6，2 ** 3 == 8
+div / mod 是关键字形式的整数除法与取余，不能用作变量名
+true / false 是值为 1 / 0 的关键字
+一元负号可以连续出现 (3 - - - 2 == 1)，优先级低于乘方：-2 ** 2 == -4，2 ** -1 中的 -1 是指数
+比较运算结果为 1 或 0，可以连写：1 < x < 10 等价于 1 < x 并且 x < 10，
+相邻的两个比较各自独立判断，1 < x > 0 表示 1 < x 并且 x > 0；每个操作数只求值一次，且都会被求值
+NUM 可以带汇编风格的进制后缀：FFh (十六进制)、1010b (二进制)
+'%' 只在 --percent 下可用，表示左边结果的百分之几：100 + 10% == 110，200 - 25% == 150；
+它只能跟在 '+' / '-' 右边的操作数后面，作用于整个操作数 (100 + 2 * 5% == 110)，百分比不是整数时按 --rounding 取整；
+不加 --percent 时 '%' 是未知字符，取余请用 mod
+分组可以用 ()、[] 或 {}，右括号必须和最近一个没闭合的左括号同类：[(1 + 2) * 3] 合法，(1 + [2 * 3)] 报错；函数调用只能用 ()
+--units h|m|s 下，十进制数字可以带时间单位后缀 h (小时)、m (分钟)、s (秒)，按所给的基本单位换算：
+--units m 时 2h + 30m == 150，90s 按 --rounding 取整为 1；这时 10h 是 10 小时而不是十六进制
+--thousands 下，十进制数字可以用逗号分组：1,000,000 + 1 == 1000001；第一组 1 到 3 位，之后每组正好 3 位，
+不符合这个格式的逗号 (1,00、1000,000) 仍是普通逗号，因此这时函数参数之间要加空格：sum(1, 000) 是两个参数
+--unsigned 下所有值都是无符号整数，范围是 0 到 2^宽度 - 1：默认 64 位时 0 - 1 在 --overflow wrap 下得
+18446744073709551615，--width 8 时得 255，默认报溢出错误；-1 同理
+--div-zero saturate 下除数为 0 不报错：1 / 0 得当前宽度的最大值，-1 / 0 得最小值，0 / 0 == 0，7 mod 0 == 7
+SUP 是写在基本表达式后面的一串上标数字，表示乘方：2² == 4，2¹⁰ == 1024，-2² == -4，(1+2)² ** 2 == 81
+/* ... */ 是注释，可以出现在任意两个 Token 之间：2 + /* 说明 */ 3 == 5；注释不能嵌套
+if(cond, a, b) 在 cond 非零时取 a，否则取 b；只求值选中的分支，if(1, 2, 1/0) == 2，
+没选中的分支只跳过到同层的 ',' 或 ')'，不检查语法
+table(body, x, lo, hi, step) 让变量 x 从 lo 开始每次加 step (默认 1，必须为正) 直到不超过 hi，每个值输出一行 "x<Tab>body 的值"，
+表达式的值是输出的行数：table(x * x, x, 0, 3) 输出 0 0、1 1、2 4、3 9，值为 4；x 只在 body 中有效，之后恢复原来的值；
+最多输出 100000 行；lo > hi 时不输出，值为 0，但 body 仍要写对：table(1 +, x, 1, 0) 报错
+NUM 也可以写成 0x / 0b 前缀形式、带 k/M/G 数量级后缀或科学计数法 1e3，各种写法的判断顺序见 get_token
 */
 
-use std::{process, io::{self, Write}};
+use std::{collections::HashMap, env, mem, panic, process, thread, io::{self, BufRead, IsTerminal, Write}, time::{Duration, Instant}};
+
+// 数字字面量的上限，是 64 位无符号整数的最大值，也不小于 i64::MIN 的绝对值，负号后面可以直接写它
+const LITERAL_MAX: i128 = u64::MAX as i128;
+
+// --max-depth 允许的最大值，以及求值线程按嵌套层数预留的栈空间
+// 未优化的构建中每层函数调用嵌套大约用 30 KB 栈，按每层 64 KB 预留，另加 1 MB 给其余部分
+const MAX_DEPTH_LIMIT: usize = 10000;
+const STACK_PER_LEVEL: usize = 64 * 1024;
+const STACK_BASE: usize = 1024 * 1024;
+
+// table 最多输出的行数
+const MAX_TABLE_ROWS: usize = 100_000;
 
 // 定义所有可能的 Token 类型
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum TokenType {
     NUMBER,
     ADD, SUB, MUL, DIV,
-    LEFTPAREN, RIGHTPAREN,
+    EXP,         // 乘方 **
+    SUPERSCRIPT, // 上标数字表示的指数，如 ²、¹⁰，值放在 number_val 中
+    BITXOR,      // 按位异或 ^
+    LT, LE, GT, GE, EQ, NE, // 比较运算符 < <= > >= == !=
+    IDIV, MOD,   // 关键字 div / mod
+    IDENT,       // 标识符
+    BOOL,        // 关键字 true / false，值放在 number_val 中
+    RESULTREF,   // $N，引用之前第 N 个结果
+    LASTRESULT,  // @，引用上一行的结果
+    LEFTPAREN, RIGHTPAREN, // 三种括号 ( [ { 和 ) ] }，具体是哪一种看 Token 起始处的字符
+    COMMA,       // 函数参数分隔符
+    PERCENT,     // --percent 下加减操作数后面的 %
+    CAPTURE,     // => 把结果保存到变量
+    PIPE,        // |> 把左边的值传给右边的函数
     END,
     UNKNOWN // 用于初始化或错误状态
 }
 
+// 整数除法 '/' 的取整方式
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RoundingMode {
+    TruncZero, // 向零截断，默认，和 Rust 的 / 一致：-7 / 2 == -3
+    FloorNeg,  // 向负无穷取整：-7 / 2 == -4
+    Nearest,   // 四舍五入，恰好一半时远离零：7 / 2 == 4，-7 / 2 == -4
+    Ceil,      // 向正无穷取整：7 / 2 == 4，-7 / 2 == -3
+    HalfEven,  // 四舍六入，恰好一半时取偶数 (银行家舍入)：5 / 2 == 2，7 / 2 == 4，-5 / 2 == -2
+}
+
+impl RoundingMode {
+    fn parse(name: &str) -> Option<RoundingMode> {
+        match name {
+            "trunc" => Some(RoundingMode::TruncZero),
+            "floor" => Some(RoundingMode::FloorNeg),
+            "nearest" => Some(RoundingMode::Nearest),
+            "ceil" => Some(RoundingMode::Ceil),
+            "half-even" => Some(RoundingMode::HalfEven),
+            _ => None,
+        }
+    }
+
+    // 按取整方式计算 a / b，调用前已保证 b != 0
+    // 用 i128 计算，i64::MIN / -1 这类结果是否溢出交给调用方按整数宽度判断
+    fn divide(self, a: i128, b: i128) -> i128 {
+        let (quot, rem) = (a / b, a % b);
+        let negative = (a < 0) != (b < 0); // 精确商是否为负
+
+        match self {
+            _ if rem == 0 => quot,
+            RoundingMode::TruncZero => quot,
+            RoundingMode::FloorNeg => if negative { quot - 1 } else { quot },
+            RoundingMode::Ceil => if negative { quot } else { quot + 1 },
+            RoundingMode::Nearest => {
+                if 2 * rem.abs() >= b.abs() {
+                    if negative { quot - 1 } else { quot + 1 }
+                } else {
+                    quot
+                }
+            },
+            RoundingMode::HalfEven => {
+                // 恰好一半时 quot 是奇数才远离零，这样结果总是偶数
+                if 2 * rem.abs() > b.abs() || (2 * rem.abs() == b.abs() && quot % 2 != 0) {
+                    if negative { quot - 1 } else { quot + 1 }
+                } else {
+                    quot
+                }
+            },
+        }
+    }
+}
+
+// 超出整数宽度时的处理方式
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum OverflowMode {
+    Checked,  // 报错，默认
+    Wrapping, // 按位宽回绕，和硬件上的定长整数一样
+}
+
+// '/'、div、mod 的除数为 0 时的处理方式
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum DivZeroPolicy {
+    Error,    // 报错，默认
+    Saturate, // 商取当前宽度的最大值或最小值 (被除数为 0 时取 0)，取余得被除数本身
+}
+
+// 结果的输出进制
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum OutputBase {
+    Fixed(u32), // 固定用 2、8、10 或 16 进制输出
+    Auto,       // 如果所有数字字面量都用同一种非十进制写法，就用那种进制输出，否则十进制
+}
+
+// 运算符的结合方式
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Assoc {
+    Left,    // 左结合：1 - 2 - 3 == (1 - 2) - 3
+    Right,   // 右结合：2 ** 3 ** 2 == 2 ** (3 ** 2)
+    Chain,   // 连写：1 < x < 10 等价于 1 < x 并且 x < 10
+    Prefix,  // 前缀一元运算符
+    Postfix, // 后缀一元运算符
+}
+
+// 一个运算符的说明，帮助信息从这里生成，不要另写一份
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct OpInfo {
+    symbol: &'static str,
+    precedence: u8, // 数字越大结合得越紧
+    assoc: Assoc,
+    zh: &'static str, // 中文说明
+    en: &'static str, // 英文说明
+}
+
+impl OpInfo {
+    fn description(&self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::Zh => self.zh,
+            Lang::En => self.en,
+        }
+    }
+}
+
+// 所有运算符，按优先级从低到高排列，修改语法时同步修改这里
+static OPERATORS: [OpInfo; 18] = [
+    OpInfo { symbol: "|>", precedence: 0, assoc: Assoc::Left, zh: "管道，x |> f 等价于 f(x)", en: "pipe, x |> f means f(x)" },
+    OpInfo { symbol: "^", precedence: 1, assoc: Assoc::Left, zh: "按位异或", en: "bitwise xor" },
+    OpInfo { symbol: "<", precedence: 2, assoc: Assoc::Chain, zh: "小于", en: "less than" },
+    OpInfo { symbol: "<=", precedence: 2, assoc: Assoc::Chain, zh: "小于等于", en: "less than or equal" },
+    OpInfo { symbol: ">", precedence: 2, assoc: Assoc::Chain, zh: "大于", en: "greater than" },
+    OpInfo { symbol: ">=", precedence: 2, assoc: Assoc::Chain, zh: "大于等于", en: "greater than or equal" },
+    OpInfo { symbol: "==", precedence: 2, assoc: Assoc::Chain, zh: "等于", en: "equal" },
+    OpInfo { symbol: "!=", precedence: 2, assoc: Assoc::Chain, zh: "不等于", en: "not equal" },
+    OpInfo { symbol: "+", precedence: 3, assoc: Assoc::Left, zh: "加", en: "add" },
+    OpInfo { symbol: "-", precedence: 3, assoc: Assoc::Left, zh: "减", en: "subtract" },
+    OpInfo { symbol: "%", precedence: 4, assoc: Assoc::Postfix, zh: "百分比，只在 --percent 下可用，跟在加减的右操作数后面：100 + 10% == 110",
+             en: "percent, only with --percent, after the right operand of + or -: 100 + 10% == 110" },
+    OpInfo { symbol: "*", precedence: 5, assoc: Assoc::Left, zh: "乘", en: "multiply" },
+    OpInfo { symbol: "/", precedence: 5, assoc: Assoc::Left, zh: "除，按 --rounding 取整", en: "divide, rounded per --rounding" },
+    OpInfo { symbol: "div", precedence: 5, assoc: Assoc::Left, zh: "整数除法，向零取整", en: "integer division, truncated toward zero" },
+    OpInfo { symbol: "mod", precedence: 5, assoc: Assoc::Left, zh: "取余", en: "remainder" },
+    OpInfo { symbol: "-", precedence: 6, assoc: Assoc::Prefix, zh: "负号", en: "negation" },
+    OpInfo { symbol: "**", precedence: 7, assoc: Assoc::Right, zh: "乘方", en: "power" },
+    OpInfo { symbol: "²", precedence: 8, assoc: Assoc::Postfix, zh: "上标乘方，跟在基本表达式后面：2¹⁰ == 1024，(1+2)² ** 2 == 81",
+             en: "superscript power, after a primary expression: 2¹⁰ == 1024, (1+2)² ** 2 == 81" },
+];
+
+fn operators() -> &'static [OpInfo] {
+    &OPERATORS
+}
+
+// 语法规则，结构化跟踪记录的就是这些规则的进入顺序
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Rule {
+    Expr,
+    BitXorExpr,
+    CompareExpr,
+    AddSubExpr,
+    MulDivExpr,
+    UnaryExpr,
+    PowExpr,
+    PrimaryExpr,
+    Call,
+}
+
+impl Rule {
+    // 所有规则，按语法中的层次排列
+    const ALL: [Rule; 9] = [
+        Rule::Expr, Rule::BitXorExpr, Rule::CompareExpr, Rule::AddSubExpr, Rule::MulDivExpr,
+        Rule::UnaryExpr, Rule::PowExpr, Rule::PrimaryExpr, Rule::Call,
+    ];
+
+    // 调试输出中使用的名称
+    fn display_name(self, lang: Lang) -> &'static str {
+        match (lang, self) {
+            (Lang::Zh, Rule::Expr) => "表达式",
+            (Lang::Zh, Rule::BitXorExpr) => "异或表达式",
+            (Lang::Zh, Rule::CompareExpr) => "比较表达式",
+            (Lang::Zh, Rule::AddSubExpr) => "加减表达式",
+            (Lang::Zh, Rule::MulDivExpr) => "乘除表达式",
+            (Lang::Zh, Rule::UnaryExpr) => "一元表达式",
+            (Lang::Zh, Rule::PowExpr) => "乘方表达式",
+            (Lang::Zh, Rule::PrimaryExpr) => "基本表达式",
+            (Lang::Zh, Rule::Call) => "函数调用",
+            (Lang::En, Rule::Expr) => "expression",
+            (Lang::En, Rule::BitXorExpr) => "xor expression",
+            (Lang::En, Rule::CompareExpr) => "comparison",
+            (Lang::En, Rule::AddSubExpr) => "additive expression",
+            (Lang::En, Rule::MulDivExpr) => "multiplicative expression",
+            (Lang::En, Rule::UnaryExpr) => "unary expression",
+            (Lang::En, Rule::PowExpr) => "power expression",
+            (Lang::En, Rule::PrimaryExpr) => "primary expression",
+            (Lang::En, Rule::Call) => "function call",
+        }
+    }
+}
+
+// 一条跟踪记录：进入了哪条规则、当时的 Token 位置，以及这条规则最终求出的值
+#[derive(Debug, PartialEq, Clone)]
+struct TraceEvent {
+    rule: Rule,
+    position: usize,    // 进入规则时当前 Token 的起始字符下标
+    value: Option<i128>, // 规则求值完成后填入
+}
+
+// 所有可能的错误，每种错误都有一个稳定的错误码，方便工具按错误码处理而不必解析提示文字
+#[derive(Debug, PartialEq, Clone)]
+enum CalcError {
+    UnknownChar(char),       // 无法识别的字符
+    Overflow,                // 数字超出范围
+    DivByZero,               // 除零
+    BudgetExceeded(u64),     // 运算次数超出上限
+    UnexpectedEof,           // 表达式意外结束
+    MissingRightParen(char), // 缺少右括号
+    BadPrimary,              // 非法基本表达式起始
+    TrailingChars { column: usize, found: String }, // 表达式后存在多余字符
+    KeywordAsName(String),   // 把关键字当作变量名
+    UnknownIdent(String),    // 未知标识符
+    UnknownFunction(String), // 未知函数
+    Arity { name: String, expected: usize, got: usize }, // 函数参数个数不对
+    AdjacentNumbers,         // 严格模式下两个数字之间缺少运算符
+    BadResultRef { index: usize, count: usize }, // $N 引用的结果不存在
+    NegativeExponent,        // 整数乘方的指数为负
+    OperatorSequence(&'static str, &'static str), // 两个二元运算符相连
+    RootOfNegative,          // 负数开偶次方
+    BadRootDegree,           // 开方次数不是正整数
+    UnterminatedComment,     // /* 注释没有结束
+    BadCaptureName,          // '=>' 后面不是变量名
+    NoPreviousResult,        // 第一行就使用了 @
+    StrayPercent,            // '%' 不在加减号右边的操作数后面
+    TooFewArgs { name: String, min: usize, got: usize }, // 可变参数函数的参数太少
+    BracketMismatch { open: char, open_column: usize, found: char }, // 右括号和左括号不是同一种
+    BadBase(i128),           // tobase 的进制超出 2 到 36
+    DisabledInSandbox(String), // 沙箱模式下调用了有副作用的函数
+    LeadingOperator(&'static str), // 表达式以二元运算符开头
+    FractionalLiteral,       // --strict-int 下出现小数点或科学计数法
+    IdentifierTooLong { len: usize, max: usize }, // 标识符超过长度上限
+    TooDeep(usize),          // 嵌套层数超过 --max-depth
+    BadPipeTarget,           // '|>' 后面不是函数名
+    BadLoopVar,              // table 的第二个参数不是变量名
+    BadStep(i128),           // table 的步长不是正数
+    ReduceOverflow(usize),   // --reduce 合并到第 N 个参数时超出范围
+    TooManyRows(usize),      // table 的行数超过上限
+}
+
+// 求值失败时返回的错误，带上出错的字符下标，报错时据此标出位置
+#[derive(Debug, PartialEq, Clone)]
+struct EvalError {
+    index: usize,
+    err: CalcError,
+}
+
+// 求值过程中产生的输出。求值本身不直接读写标准输入输出，由调用方按顺序写出
+#[derive(Debug, PartialEq, Clone)]
+enum Output {
+    Stdout(String), // 调试信息、divmod 的商和余数
+    Stderr(String), // 截断提示
+}
+
+impl CalcError {
+    // 机器可读的错误码
+    fn code(&self) -> &'static str {
+        match self {
+            CalcError::UnknownChar(_) => "E_UNKNOWN_CHAR",
+            CalcError::Overflow => "E_OVERFLOW",
+            CalcError::DivByZero => "E_DIV_ZERO",
+            CalcError::BudgetExceeded(_) => "E_BUDGET",
+            CalcError::UnexpectedEof => "E_EOF",
+            CalcError::MissingRightParen(_) |
+            CalcError::BracketMismatch { .. } |
+            CalcError::BadPrimary |
+            CalcError::TrailingChars { .. } => "E_UNEXPECTED_TOKEN",
+            CalcError::KeywordAsName(_) => "E_KEYWORD",
+            CalcError::UnknownIdent(_) => "E_UNKNOWN_IDENT",
+            CalcError::UnknownFunction(_) => "E_UNKNOWN_FUNC",
+            CalcError::Arity { .. } => "E_ARITY",
+            CalcError::AdjacentNumbers => "E_ADJACENT_NUMBERS",
+            CalcError::BadResultRef { .. } => "E_BAD_REF",
+            CalcError::NegativeExponent => "E_NEG_EXP",
+            CalcError::OperatorSequence(..) => "E_OPERATOR_SEQUENCE",
+            CalcError::RootOfNegative => "E_DOMAIN",
+            CalcError::BadRootDegree => "E_DOMAIN",
+            CalcError::UnterminatedComment => "E_UNTERMINATED_COMMENT",
+            CalcError::BadCaptureName => "E_UNEXPECTED_TOKEN",
+            CalcError::NoPreviousResult => "E_BAD_REF",
+            CalcError::StrayPercent => "E_UNEXPECTED_TOKEN",
+            CalcError::TooFewArgs { .. } => "E_ARITY",
+            CalcError::BadBase(_) => "E_DOMAIN",
+            CalcError::DisabledInSandbox(_) => "E_SANDBOX",
+            CalcError::LeadingOperator(_) => "E_OPERATOR_SEQUENCE",
+            CalcError::FractionalLiteral => "E_STRICT_INT",
+            CalcError::IdentifierTooLong { .. } => "E_IDENT_TOO_LONG",
+            CalcError::TooDeep(_) => "E_TOO_DEEP",
+            CalcError::BadPipeTarget => "E_UNEXPECTED_TOKEN",
+            CalcError::BadLoopVar => "E_UNEXPECTED_TOKEN",
+            CalcError::BadStep(_) => "E_DOMAIN",
+            CalcError::ReduceOverflow(_) => "E_OVERFLOW",
+            CalcError::TooManyRows(_) => "E_BUDGET",
+        }
+    }
+
+    // 只看原文就能发现的错误：写法不对、函数名或参数个数不对、嵌套太深，和代入什么值无关
+    fn is_syntax(&self) -> bool {
+        matches!(self,
+            CalcError::UnknownChar(_) | CalcError::UnexpectedEof | CalcError::MissingRightParen(_) |
+            CalcError::BracketMismatch { .. } | CalcError::BadPrimary | CalcError::TrailingChars { .. } |
+            CalcError::KeywordAsName(_) | CalcError::UnknownFunction(_) | CalcError::Arity { .. } |
+            CalcError::TooFewArgs { .. } | CalcError::AdjacentNumbers | CalcError::OperatorSequence(..) |
+            CalcError::UnterminatedComment | CalcError::BadCaptureName | CalcError::StrayPercent |
+            CalcError::LeadingOperator(_) | CalcError::FractionalLiteral | CalcError::IdentifierTooLong { .. } |
+            CalcError::TooDeep(_) | CalcError::BadPipeTarget | CalcError::BadLoopVar)
+    }
+
+    // 给用户看的错误信息
+    fn message(&self, lang: Lang) -> String {
+        match lang {
+            Lang::Zh => match self {
+                CalcError::UnknownChar(c) => format!("未知 Token: {}", c),
+                CalcError::Overflow => "数字超出范围".to_string(),
+                CalcError::DivByZero => "除零错误".to_string(),
+                CalcError::BudgetExceeded(limit) => format!("运算次数超出上限 ({})", limit),
+                CalcError::UnexpectedEof => "表达式意外结束".to_string(),
+                CalcError::MissingRightParen(close) => format!("缺少右括号 '{}'", close),
+                CalcError::BadPrimary => "非法基本表达式起始 (期望数字、'-' 或 '(')".to_string(),
+                CalcError::TrailingChars { column, found } => format!("表达式后存在多余字符: 第 {} 列的 '{}'", column, found),
+                CalcError::KeywordAsName(name) => format!("'{}' 是关键字，不能用作变量名", name),
+                CalcError::UnknownIdent(name) => format!("未知标识符: {}", name),
+                CalcError::UnknownFunction(name) => format!("未知函数: {}", name),
+                CalcError::Arity { name, expected, got } => format!("函数 {} 需要 {} 个参数，实际给了 {} 个", name, expected, got),
+                CalcError::AdjacentNumbers => "相邻数字缺少运算符".to_string(),
+                CalcError::BadResultRef { index, count } => format!("结果引用 ${} 不存在 (目前共有 {} 个结果)", index, count),
+                CalcError::NegativeExponent => "整数乘方的指数不能为负".to_string(),
+                CalcError::OperatorSequence(first, second) => format!("运算符 '{}' 后不应紧跟 '{}'，这里需要一个操作数 (只有 '-' 可以用作一元运算符)", first, second),
+                CalcError::RootOfNegative => "负数不能开偶次方".to_string(),
+                CalcError::BadRootDegree => "开方次数必须是正整数".to_string(),
+                CalcError::UnterminatedComment => "注释 /* 没有对应的 */".to_string(),
+                CalcError::BadCaptureName => "'=>' 后面需要一个变量名".to_string(),
+                CalcError::NoPreviousResult => "没有上一行结果".to_string(),
+                CalcError::StrayPercent => "'%' 只能跟在 '+' 或 '-' 右边的操作数后面".to_string(),
+                CalcError::TooFewArgs { name, min, got } => format!("函数 {} 至少需要 {} 个参数，实际给了 {} 个", name, min, got),
+                CalcError::BracketMismatch { open, open_column, found } => format!("括号类型不匹配: 第 {} 列的 '{}' 需要用 '{}' 闭合，这里是 '{}'", open_column, open, closing_bracket(*open), found),
+                CalcError::BadBase(base) => format!("进制 {} 超出范围 (只支持 2 到 36)", base),
+                CalcError::DisabledInSandbox(name) => format!("函数 {} 在沙箱模式下被禁用", name),
+                CalcError::LeadingOperator(op) => format!("表达式不能以运算符 '{}' 开头 (只有 '-' 可以用作一元运算符)", op),
+                CalcError::FractionalLiteral => "此模式下不允许小数".to_string(),
+                CalcError::IdentifierTooLong { len, max } => format!("标识符太长 ({} 个字符，上限 {})", len, max),
+                CalcError::TooDeep(limit) => format!("嵌套太深 (上限 {} 层)", limit),
+                CalcError::BadPipeTarget => "'|>' 后面需要一个函数名".to_string(),
+                CalcError::BadLoopVar => "table 的第二个参数需要一个变量名".to_string(),
+                CalcError::BadStep(step) => format!("table 的步长必须是正数，实际是 {}", step),
+                CalcError::ReduceOverflow(n) => format!("合并到第 {} 个参数时超出范围", n),
+                CalcError::TooManyRows(max) => format!("table 最多输出 {} 行", max),
+            },
+            Lang::En => match self {
+                CalcError::UnknownChar(c) => format!("unknown token: {}", c),
+                CalcError::Overflow => "number out of range".to_string(),
+                CalcError::DivByZero => "division by zero".to_string(),
+                CalcError::BudgetExceeded(limit) => format!("operation budget exceeded ({})", limit),
+                CalcError::UnexpectedEof => "unexpected end of expression".to_string(),
+                CalcError::MissingRightParen(close) => format!("missing closing bracket '{}'", close),
+                CalcError::BadPrimary => "invalid start of expression (expected a number, '-' or '(')".to_string(),
+                CalcError::TrailingChars { column, found } => format!("unexpected characters after expression: '{}' at column {}", found, column),
+                CalcError::KeywordAsName(name) => format!("'{}' is a keyword and cannot be used as a name", name),
+                CalcError::UnknownIdent(name) => format!("unknown identifier: {}", name),
+                CalcError::UnknownFunction(name) => format!("unknown function: {}", name),
+                CalcError::Arity { name, expected, got } => format!("function {} expects {} argument(s), got {}", name, expected, got),
+                CalcError::AdjacentNumbers => "missing operator between adjacent numbers".to_string(),
+                CalcError::BadResultRef { index, count } => format!("result reference ${} does not exist ({} result(s) so far)", index, count),
+                CalcError::NegativeExponent => "integer power requires a non-negative exponent".to_string(),
+                CalcError::OperatorSequence(first, second) => format!("operator '{}' cannot be directly followed by '{}'; an operand is expected here (only '-' may be used as a unary operator)", first, second),
+                CalcError::RootOfNegative => "cannot take an even root of a negative number".to_string(),
+                CalcError::BadRootDegree => "root degree must be a positive integer".to_string(),
+                CalcError::UnterminatedComment => "unterminated comment: /* without matching */".to_string(),
+                CalcError::BadCaptureName => "'=>' must be followed by a variable name".to_string(),
+                CalcError::NoPreviousResult => "there is no previous line result".to_string(),
+                CalcError::StrayPercent => "'%' may only follow the right operand of '+' or '-'".to_string(),
+                CalcError::TooFewArgs { name, min, got } => format!("function {} expects at least {} argument(s), got {}", name, min, got),
+                CalcError::BracketMismatch { open, open_column, found } => format!("mismatched brackets: '{}' at column {} must be closed by '{}', found '{}'", open, open_column, closing_bracket(*open), found),
+                CalcError::BadBase(base) => format!("base {} is out of range (2 to 36)", base),
+                CalcError::DisabledInSandbox(name) => format!("function {} is disabled in sandbox mode", name),
+                CalcError::LeadingOperator(op) => format!("an expression cannot start with the operator '{}' (only '-' may be used as a unary operator)", op),
+                CalcError::FractionalLiteral => "fractional literals are not allowed in this mode".to_string(),
+                CalcError::IdentifierTooLong { len, max } => format!("identifier too long ({} characters, limit {})", len, max),
+                CalcError::TooDeep(limit) => format!("nested too deeply (limit {})", limit),
+                CalcError::BadPipeTarget => "'|>' must be followed by a function name".to_string(),
+                CalcError::BadLoopVar => "the second argument of table must be a variable name".to_string(),
+                CalcError::BadStep(step) => format!("table step must be positive, got {}", step),
+                CalcError::ReduceOverflow(n) => format!("out of range when combining argument {}", n),
+                CalcError::TooManyRows(max) => format!("table prints at most {} rows", max),
+            },
+        }
+    }
+}
+
+// 界面语言，默认中文；可以用 --lang 或环境变量 CALC_LANG 切换
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Lang {
+    Zh,
+    En,
+}
+
+impl Lang {
+    // 解析 zh / en，也接受 zh_CN.UTF-8、en-US 这类带地区的写法
+    fn parse(name: &str) -> Option<Lang> {
+        let name = name.to_ascii_lowercase();
+        if name.starts_with("zh") {
+            Some(Lang::Zh)
+        } else if name.starts_with("en") {
+            Some(Lang::En)
+        } else {
+            None
+        }
+    }
+
+    fn error_label(self) -> &'static str {
+        match self {
+            Lang::Zh => "错误",
+            Lang::En => "Error",
+        }
+    }
+
+    fn debug_label(self) -> &'static str {
+        match self {
+            Lang::Zh => "调试",
+            Lang::En => "debug",
+        }
+    }
+
+    fn hint_label(self) -> &'static str {
+        match self {
+            Lang::Zh => "提示",
+            Lang::En => "Hint",
+        }
+    }
+
+    fn prompt(self) -> &'static str {
+        match self {
+            Lang::Zh => "写下你想计算的算式: ",
+            Lang::En => "Enter an expression: ",
+        }
+    }
+
+    fn result_label(self) -> &'static str {
+        match self {
+            Lang::Zh => "结果是",
+            Lang::En => "Result",
+        }
+    }
+}
+
+// 计算器的配置，链式设置各项选项后用 build 生成 Calculator
+// 例如 CalculatorBuilder::new().debug(true).overflow(OverflowMode::Wrapping).build(src)
+#[derive(Debug, Clone, Copy)]
+struct CalculatorBuilder {
+    debug: bool,
+    fullwidth: bool,
+    lang: Lang,
+    strict: bool,
+    warn_truncation: bool,
+    rounding: RoundingMode,
+    width: u32,
+    overflow: OverflowMode,
+    output_base: OutputBase,
+    max_ops: Option<u64>,
+    percent: bool,
+    units: Option<i64>,
+    thousands: bool,
+    sandbox: bool,
+    strict_int: bool,
+    max_ident_len: usize,
+    depth_limit: usize,
+    unsigned: bool,
+    group_base: bool,
+    explain_error: bool,
+    div_zero: DivZeroPolicy,
+    implicit_ans: bool,
+    locale_separator: Option<char>,
+}
+
+impl CalculatorBuilder {
+    // 默认配置；Calculator::new 和命令行参数的默认值都取自这里
+    fn new() -> Self {
+        Self {
+            debug: false,
+            fullwidth: true,
+            lang: Lang::Zh,
+            strict: false,
+            warn_truncation: false,
+            rounding: RoundingMode::TruncZero,
+            width: 64,
+            overflow: OverflowMode::Checked,
+            output_base: OutputBase::Fixed(10),
+            max_ops: None,
+            percent: false,
+            units: None,
+            thousands: false,
+            sandbox: false,
+            strict_int: false,
+            max_ident_len: 64,
+            depth_limit: 256,
+            unsigned: false,
+            group_base: false,
+            explain_error: false,
+            div_zero: DivZeroPolicy::Error,
+            implicit_ans: false,
+            locale_separator: None,
+        }
+    }
+
+    fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    fn fullwidth(mut self, fullwidth: bool) -> Self {
+        self.fullwidth = fullwidth;
+        self
+    }
+
+    fn lang(mut self, lang: Lang) -> Self {
+        self.lang = lang;
+        self
+    }
+
+    fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    fn warn_truncation(mut self, warn: bool) -> Self {
+        self.warn_truncation = warn;
+        self
+    }
+
+    fn rounding(mut self, rounding: RoundingMode) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    fn width(mut self, width: u32) -> Self {
+        self.width = width;
+        self
+    }
+
+    fn overflow(mut self, overflow: OverflowMode) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    fn output_base(mut self, output_base: OutputBase) -> Self {
+        self.output_base = output_base;
+        self
+    }
+
+    fn max_ops(mut self, max_ops: Option<u64>) -> Self {
+        self.max_ops = max_ops;
+        self
+    }
+
+    fn percent(mut self, percent: bool) -> Self {
+        self.percent = percent;
+        self
+    }
+
+    fn units(mut self, units: Option<i64>) -> Self {
+        self.units = units;
+        self
+    }
+
+    fn thousands(mut self, thousands: bool) -> Self {
+        self.thousands = thousands;
+        self
+    }
+
+    fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    fn strict_int(mut self, strict_int: bool) -> Self {
+        self.strict_int = strict_int;
+        self
+    }
+
+    fn max_ident_len(mut self, max_ident_len: usize) -> Self {
+        self.max_ident_len = max_ident_len;
+        self
+    }
+
+    fn depth_limit(mut self, depth_limit: usize) -> Self {
+        self.depth_limit = depth_limit;
+        self
+    }
+
+    fn unsigned(mut self, unsigned: bool) -> Self {
+        self.unsigned = unsigned;
+        self
+    }
+
+    fn group_base(mut self, group_base: bool) -> Self {
+        self.group_base = group_base;
+        self
+    }
+
+    fn explain_error(mut self, explain_error: bool) -> Self {
+        self.explain_error = explain_error;
+        self
+    }
+
+    fn div_zero(mut self, div_zero: DivZeroPolicy) -> Self {
+        self.div_zero = div_zero;
+        self
+    }
+
+    fn implicit_ans(mut self, implicit_ans: bool) -> Self {
+        self.implicit_ans = implicit_ans;
+        self
+    }
+
+    fn locale_separator(mut self, locale_separator: Option<char>) -> Self {
+        self.locale_separator = locale_separator;
+        self
+    }
+
+    // 按当前配置生成一个计算器，src 是要求值的算式
+    fn build(self, src: String) -> Calculator {
+        // fullwidth 为 true 时先把全角数字和符号转换成半角再分析
+        let raw_chars: Vec<char> = src.chars().collect();
+        let src_chars = if self.fullwidth {
+            raw_chars.iter().map(|&c| to_halfwidth(c)).collect()
+        } else {
+            raw_chars.clone()
+        };
+
+        Calculator {
+            src_chars,
+            raw_chars,
+            current_index: 0,
+            token_start: 0,
+            current_token: TokenType::UNKNOWN,
+            prev_token: TokenType::UNKNOWN,
+            number_val: 0,
+            number_base: 10,
+            ident_val: String::new(),
+            debug_mode: self.debug,
+            lang: self.lang,
+            strict: self.strict,
+            warn_truncation: self.warn_truncation,
+            rounding: self.rounding,
+            width: self.width,
+            overflow: self.overflow,
+            history: Vec::new(),
+            variables: HashMap::new(),
+            literal_bases: Vec::new(),
+            output_base: self.output_base,
+            trace: Vec::new(),
+            op_count: 0,
+            token_count: 0,
+            depth: 0,
+            max_depth: 0,
+            max_nesting: 0,
+            output: Vec::new(),
+            max_ops: self.max_ops,
+            percent: self.percent,
+            units: self.units,
+            thousands: self.thousands,
+            sandbox: self.sandbox,
+            strict_int: self.strict_int,
+            max_ident_len: self.max_ident_len,
+            nesting: 0,
+            depth_limit: self.depth_limit,
+            unsigned: self.unsigned,
+            group_base: self.group_base,
+            explain_error: self.explain_error,
+            div_zero: self.div_zero,
+            comparison: false,
+            implicit_ans: self.implicit_ans,
+            pending_operand: None,
+            locale_separator: self.locale_separator,
+        }
+    }
+}
+
 // 包含所有解析器状态的结构体
 struct Calculator {
-    src_chars: Vec<char>, // 存储表达式的字符向量
+    src_chars: Vec<char>, // 存储表达式的字符向量（已做全角转换）
+    raw_chars: Vec<char>, // 用户输入的原样字符，报错时展示
     current_index: usize,
+    token_start: usize,   // 当前 Token 的起始字符下标，用于错误定位
     current_token: TokenType,
-    number_val: i32,
+    prev_token: TokenType, // 上一个 Token，严格模式用来检查相邻数字
+    number_val: i128, // 最近一次解析出的数字，范围是 0..=LITERAL_MAX
+    number_base: u32,  // 最近一次解析出的数字字面量是用几进制写的
+    ident_val: String, // 最近一次解析出的标识符
     debug_mode: bool,
+    lang: Lang,            // 提示信息使用的语言
+    strict: bool,          // 严格模式：对可疑的书写方式给出专门的错误
+    warn_truncation: bool, // 整数除法丢弃余数时输出提示
+    rounding: RoundingMode, // '/' 的取整方式
+    width: u32,            // 整数宽度 (8、16、32 或 64 位)
+    overflow: OverflowMode, // 结果超出整数宽度时的处理方式
+    history: Vec<i128>,    // 之前各行的结果，供 $1、$2 ... 引用
+    variables: HashMap<String, i128>, // 用 => 保存的变量
+    literal_bases: Vec<u32>, // 表达式中每个数字字面量的进制，按出现顺序
+    output_base: OutputBase, // 结果的输出进制
+    trace: Vec<TraceEvent>, // 结构化的求值跟踪
+    op_count: u64,         // 已执行的运算次数
+    token_count: u64,      // 已读取的 Token 个数
+    depth: usize,          // 当前所在的语法规则嵌套深度
+    max_depth: usize,      // 求值过程中达到的最大语法规则深度
+    max_nesting: usize,    // 求值过程中达到的最大嵌套层数，和 --max-depth 检查的是同一个计数
+    output: Vec<Output>,   // 求值过程中产生的输出，按产生顺序排列
+    max_ops: Option<u64>,  // 运算次数上限，None 表示不限制
+    percent: bool,         // '%' 表示左边结果的百分之几
+    units: Option<i64>,    // 时间单位的基本单位是多少秒，None 表示不识别时间单位
+    thousands: bool,       // 数字中可以用逗号做千位分隔符
+    sandbox: bool,         // 沙箱模式：禁用有副作用的函数
+    strict_int: bool,      // 拒绝小数点和科学计数法，只接受整数写法
+    max_ident_len: usize,  // 标识符的最大长度，防止粘贴进来的超长 Token
+    nesting: usize,        // 当前的嵌套层数：分组括号、函数调用的 ()、连续的负号和右结合的 ** 都算一层
+    depth_limit: usize,    // 嵌套层数上限，防止递归太深时栈溢出
+    unsigned: bool,        // 按无符号整数计算，结果不能为负
+    group_base: bool,      // 非十进制结果用 '_' 分组
+    explain_error: bool,   // 报错时附带一行修改建议
+    div_zero: DivZeroPolicy, // 除数为 0 时的处理方式
+    comparison: bool,      // 刚求出的值是不是直接来自比较 (只隔着分组括号)，--assert 只检查这样的行
+    implicit_ans: bool,    // 以二元运算符开头时用上一行的结果作左操作数
+    pending_operand: Option<i128>, // 下一个基本表达式直接取这个值，不读 Token
+    locale_separator: Option<char>, // --locale 给出的十进制结果千位分隔符，None 表示不分组
 }
 
 impl Calculator {
-    // 构造函数
-    fn new(src: String, debug: bool) -> Self {
-        Self {
-            src_chars: src.chars().collect(),
-            current_index: 0,
-            current_token: TokenType::UNKNOWN,
-            number_val: 0,
-            debug_mode: debug,
-        }
+    // 构造函数，其余选项取默认值，需要调整时用 CalculatorBuilder
+    // fullwidth 为 true 时先把全角数字和符号转换成半角再分析
+    // 程序内部都通过 CalculatorBuilder 构造，这里只是保留原来的写法
+    #[allow(dead_code)]
+    fn new(src: String, debug: bool, fullwidth: bool) -> Self {
+        CalculatorBuilder::new().debug(debug).fullwidth(fullwidth).build(src)
     }
 
     // 调试输出
-    fn debug(&self, message: &str) {
+    fn debug(&mut self, message: &str) {
         if self.debug_mode {
-            println!("[调试] {}", message);
+            self.output.push(Output::Stdout(format!("[{}] {}", self.lang.debug_label(), message)));
         }
     }
 
-    // 错误处理，停止程序
-    fn error(&self, message: &str) -> ! {
-        eprintln!("错误: {}", message);
-        process::exit(1);
+    // 进入一条语法规则：记录跟踪事件，调试模式下同时打印出来。返回事件的下标
+    fn enter(&mut self, rule: Rule) -> usize {
+        let message = match self.lang {
+            Lang::Zh => format!("求值: {}", rule.display_name(self.lang)),
+            Lang::En => format!("eval: {}", rule.display_name(self.lang)),
+        };
+        self.debug(&message);
+        self.trace.push(TraceEvent { rule, position: self.token_start, value: None });
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        self.trace.len() - 1
+    }
+
+    // 离开一条语法规则，把求出的值补进对应的跟踪事件
+    fn leave(&mut self, event: usize, value: i128) -> i128 {
+        self.trace[event].value = Some(value);
+        self.depth -= 1;
+        value
+    }
+
+    // 在当前 Token 处报错
+    fn error<T>(&self, err: CalcError) -> Result<T, EvalError> {
+        self.error_at(self.token_start, err)
+    }
+
+    // 在指定字符下标处报错
+    fn error_at<T>(&self, index: usize, err: CalcError) -> Result<T, EvalError> {
+        Err(EvalError { index, err })
+    }
+
+    // 把错误写到 stderr，并在原式下方用 ^ 标出位置
+    // color 为 true 时错误标签和 ^ 用红色显示，否则不输出任何 ANSI 转义序列
+    fn report(&self, error: &EvalError, color: bool) {
+        let (red, reset) = if color { ("\x1b[1;31m", "\x1b[0m") } else { ("", "") };
+        let src: String = self.raw_chars.iter().collect();
+        // 按显示宽度而不是字符个数计算 ^ 的偏移，这样全角字符、中文也能对齐
+        let column: usize = self.raw_chars[..error.index.min(self.raw_chars.len())]
+            .iter()
+            .map(|&c| char_width(c))
+            .sum();
+
+        eprintln!("{}{}[{}]{}: {}", red, self.lang.error_label(), error.err.code(), reset, error.err.message(self.lang));
+        eprintln!("  {}", src);
+        eprintln!("  {}{}^{}", " ".repeat(column), red, reset);
+        if self.explain_error {
+            if let Some(hint) = self.hint(error) {
+                eprintln!("{}: {}", self.lang.hint_label(), hint);
+            }
+        }
+    }
+
+    // --explain-error 的修改建议，只覆盖能给出具体改法的几种错误
+    fn hint(&self, error: &EvalError) -> Option<String> {
+        match &error.err {
+            CalcError::UnknownFunction(name) | CalcError::UnknownIdent(name) => {
+                // 在函数名、已保存的变量和 true / false 中找编辑距离最近的一个，差得太远就不猜
+                let known = capabilities().functions.iter().copied()
+                    .chain(self.variables.keys().map(String::as_str))
+                    .chain(["true", "false"]);
+                let (best, distance) = known
+                    .map(|candidate| (candidate, edit_distance(name, candidate)))
+                    .min_by_key(|&(_, distance)| distance)?;
+                if distance == 0 || distance > 2 || distance >= name.chars().count() {
+                    return None;
+                }
+                Some(match self.lang {
+                    Lang::Zh => format!("是不是想写 {}？", best),
+                    Lang::En => format!("did you mean {}?", best),
+                })
+            },
+            CalcError::MissingRightParen(close) => Some(if error.index >= self.src_chars.len() {
+                match self.lang {
+                    Lang::Zh => format!("在末尾补上 '{}'", close),
+                    Lang::En => format!("add '{}' at the end", close),
+                }
+            } else {
+                match self.lang {
+                    Lang::Zh => format!("在第 {} 列之前补上 '{}'", error.index + 1, close),
+                    Lang::En => format!("add '{}' before column {}", close, error.index + 1),
+                }
+            }),
+            CalcError::UnexpectedEof => {
+                // 算式以二元运算符结尾，如 1 +
+                let op = binary_op_text(self.prev_token)?;
+                Some(match self.lang {
+                    Lang::Zh => format!("删掉末尾的 '{}'，或者在它后面补上操作数", op),
+                    Lang::En => format!("remove the trailing '{}' or add an operand after it", op),
+                })
+            },
+            _ => None,
+        }
     }
 
     // 词法分析器：获取下一个 Token
-    fn get_token(&mut self) {
-        // 跳过空白字符
-        while self.current_index < self.src_chars.len() &&
-            self.src_chars[self.current_index].is_whitespace()
-        {
-            self.current_index += 1;
+    fn get_token(&mut self) -> Result<(), EvalError> {
+        self.prev_token = self.current_token;
+
+        // 跳过空白字符和 /* ... */ 注释
+        loop {
+            while self.current_index < self.src_chars.len() &&
+                self.src_chars[self.current_index].is_whitespace()
+            {
+                self.current_index += 1;
+            }
+
+            if !self.src_chars[self.current_index..].starts_with(&['/', '*']) {
+                break;
+            }
+            // 注释不能嵌套，遇到的第一个 */ 就结束注释；没有 */ 时在 /* 处报错
+            let comment_start = self.current_index;
+            match self.src_chars[comment_start + 2..].windows(2).position(|w| w == ['*', '/']) {
+                Some(offset) => self.current_index = comment_start + 2 + offset + 2,
+                None => return self.error_at(comment_start, CalcError::UnterminatedComment),
+            }
         }
 
+        self.token_start = self.current_index;
+        self.token_count += 1;
+
         if self.current_index >= self.src_chars.len() {
             self.current_token = TokenType::END;
-            self.debug("Token: 结束");
-            return;
+            self.debug(match self.lang {
+                Lang::Zh => "Token: 结束",
+                Lang::En => "Token: end",
+            });
+            return Ok(());
         }
 
         let current_char = self.src_chars[self.current_index];
@@ -71,161 +941,1969 @@ impl Calculator {
         self.current_token = match current_char {
             '+' => TokenType::ADD,
             '-' => TokenType::SUB,
-            '*' => TokenType::MUL,
+            '⁰' | '¹' | '²' | '³' | '⁴'..='⁹' => {
+                // 连续的上标数字合成一个指数：2¹⁰ 的指数是 10
+                let start_index = self.current_index;
+                let digits: Vec<char> = self.src_chars[start_index..].iter().map_while(|&c| superscript_digit(c)).collect();
+                self.number_val = self.parse_digits(&digits, 10)?;
+                self.current_index = start_index + digits.len();
+                TokenType::SUPERSCRIPT
+            },
+            '*' => {
+                // 紧挨着的两个 * 是乘方；中间有空格的 * * 仍是两个乘号
+                if self.src_chars.get(self.current_index + 1) == Some(&'*') {
+                    self.current_index += 1;
+                    TokenType::EXP
+                } else {
+                    TokenType::MUL
+                }
+            },
             '/' => TokenType::DIV,
-            '(' => TokenType::LEFTPAREN,
-            ')' => TokenType::RIGHTPAREN,
-            '0'..='9' => {
-                // 解析数字
-                self.number_val = 0;
+            '^' => TokenType::BITXOR,
+            '=' if self.src_chars.get(self.current_index + 1) == Some(&'>') => {
+                self.current_index += 1;
+                TokenType::CAPTURE
+            },
+            '|' if self.src_chars.get(self.current_index + 1) == Some(&'>') => {
+                self.current_index += 1;
+                TokenType::PIPE
+            },
+            '<' | '>' | '=' | '!' => {
+                // 比较运算符，两个字符的先看后面是不是 '='
+                let followed_by_eq = self.src_chars.get(self.current_index + 1) == Some(&'=');
+                if followed_by_eq {
+                    self.current_index += 1;
+                }
+
+                match (current_char, followed_by_eq) {
+                    ('<', false) => TokenType::LT,
+                    ('<', true) => TokenType::LE,
+                    ('>', false) => TokenType::GT,
+                    ('>', true) => TokenType::GE,
+                    ('=', true) => TokenType::EQ,
+                    ('!', true) => TokenType::NE,
+                    _ => return self.error(CalcError::UnknownChar(current_char)),
+                }
+            },
+            '(' | '[' | '{' => TokenType::LEFTPAREN,
+            ')' | ']' | '}' => TokenType::RIGHTPAREN,
+            ',' => TokenType::COMMA,
+            '%' if self.percent => TokenType::PERCENT,
+            '@' => TokenType::LASTRESULT,
+            '.' if self.strict_int => return self.error(CalcError::FractionalLiteral),
+            '$' => {
+                // $N：引用之前第 N 个结果
+                let start_index = self.current_index + 1;
+                let digit_count = self.src_chars[start_index..].iter().take_while(|c| c.is_ascii_digit()).count();
+                if digit_count == 0 {
+                    return self.error(CalcError::UnknownChar('$'));
+                }
+
+                self.number_val = self.parse_digits(&self.src_chars[start_index..start_index + digit_count], 10)?;
+                self.current_index = start_index + digit_count;
+                TokenType::RESULTREF
+            },
+            '0'..='9' | 'a'..='z' | 'A'..='Z' | '_' => {
+                // 先取出完整的字母数字串，再判断它是数字、带进制后缀的数字还是标识符
                 let start_index = self.current_index;
 
                 while self.current_index < self.src_chars.len() &&
-                    self.src_chars[self.current_index].is_digit(10)
+                    (self.src_chars[self.current_index].is_ascii_alphanumeric() ||
+                     self.src_chars[self.current_index] == '_')
                 {
-                    // 将字符转换为数字并累加
-                    let digit = self.src_chars[self.current_index].to_digit(10).unwrap();
-                    self.number_val = self.number_val * 10 + (digit as i32);
                     self.current_index += 1;
                 }
 
-                // 重置索引以进行统一推进（实际上数字已经在上面移动了）
-                self.current_index = start_index;
-                TokenType::NUMBER
+                let word: Vec<char> = self.src_chars[start_index..self.current_index].to_vec();
+
+                // 数字字面量按以下顺序判断，先匹配的优先：
+                //   0. 开启 --thousands 时用逗号分组的十进制数：1,000,000
+                //      开启 --units 时的时间单位后缀：2h、30m、90s
+                //   1. 0x / 0b 前缀：0xe3 是十六进制 227，前缀之后不再做科学计数法解释
+                //   2. h / b 后缀：FFh、1010b
+                //   3. 数量级后缀：3k == 3000，2M == 2000000，1G == 1000000000
+                //   4. 科学计数法：1e3 == 1000（整数模式下指数只能是非负整数）
+                //   5. 普通十进制
+                self.number_base = 10;
+                let grouped_len = if self.thousands { thousands_group_len(&self.src_chars[start_index..]) } else { None };
+                if let Some(len) = grouped_len {
+                    let digits: Vec<char> = self.src_chars[start_index..start_index + len].iter().copied().filter(|&c| c != ',').collect();
+                    self.number_val = self.parse_digits(&digits, 10)?;
+                    self.current_index = start_index + len;
+                    TokenType::NUMBER
+                } else if let Some((digits, seconds)) = self.units.and(split_time_unit(&word)) {
+                    let base = self.units.unwrap();
+                    let value = self.parse_digits(digits, 10)?;
+                    self.number_val = self.rounding.divide(value * seconds as i128, base as i128);
+                    if self.number_val > LITERAL_MAX {
+                        return self.error(CalcError::Overflow);
+                    }
+                    TokenType::NUMBER
+                } else if let Some((digits, radix)) = split_base_prefix(&word) {
+                    self.number_val = self.parse_digits(digits, radix)?;
+                    self.number_base = radix;
+                    TokenType::NUMBER
+                } else if let Some((digits, radix)) = split_base_suffix(&word) {
+                    // 汇编风格的进制后缀：FFh 为十六进制，1010b 为二进制
+                    self.number_val = self.parse_digits(digits, radix)?;
+                    self.number_base = radix;
+                    TokenType::NUMBER
+                } else if let Some((digits, scale)) = split_scale_suffix(&word) {
+                    let value = self.parse_digits(digits, 10)?;
+                    self.number_val = match value.checked_mul(scale as i128).filter(|&v| v <= LITERAL_MAX) {
+                        Some(v) => v,
+                        None => return self.error(CalcError::Overflow),
+                    };
+                    TokenType::NUMBER
+                } else if let Some((mantissa, exponent)) = split_exponent(&word) {
+                    if self.strict_int {
+                        return self.error(CalcError::FractionalLiteral);
+                    }
+                    let mantissa = self.parse_digits(mantissa, 10)?;
+                    let exponent = self.parse_digits(exponent, 10)?;
+                    // 指数超出 u32 时 10 的幂一定溢出，不能截断成 u32 再算
+                    self.number_val = match u32::try_from(exponent).ok()
+                        .and_then(|e| 10i128.checked_pow(e))
+                        .and_then(|p| mantissa.checked_mul(p))
+                        .filter(|&v| v <= LITERAL_MAX)
+                    {
+                        Some(v) => v,
+                        None => return self.error(CalcError::Overflow),
+                    };
+                    TokenType::NUMBER
+                } else if current_char.is_ascii_digit() {
+                    // 普通十进制数字，只取开头的数字部分，后面的字母留给下一个 Token
+                    let digit_count = word.iter().take_while(|c| c.is_ascii_digit()).count();
+                    self.number_val = self.parse_digits(&word[..digit_count], 10)?;
+                    self.current_index = start_index + digit_count;
+                    TokenType::NUMBER
+                } else {
+                    // 解析标识符，关键字 div / mod 也在这里识别
+                    if word.len() > self.max_ident_len {
+                        return self.error(CalcError::IdentifierTooLong { len: word.len(), max: self.max_ident_len });
+                    }
+                    self.ident_val = word.iter().collect();
+
+                    match self.ident_val.as_str() {
+                        "div" => TokenType::IDIV,
+                        "mod" => TokenType::MOD,
+                        "true" | "false" => {
+                            self.number_val = (self.ident_val == "true") as i128;
+                            TokenType::BOOL
+                        },
+                        _ => TokenType::IDENT,
+                    }
+                }
             },
-            _ => self.error(&format!("未知 Token: {}", current_char)),
+            _ => return self.error(CalcError::UnknownChar(current_char)),
         };
 
-        // 统一推进索引
-        if self.current_token != TokenType::NUMBER {
-            self.current_index += 1;
-        } else {
-            // 对于 NUMBER Token，需要移动到数字的末尾
-            while self.current_index < self.src_chars.len() &&
-                self.src_chars[self.current_index].is_digit(10)
-            {
-                self.current_index += 1;
-            }
+        // 严格模式下，用空格隔开的两个数字 (如 1 2) 不会被当成两个独立的操作数
+        if self.strict && self.current_token == TokenType::NUMBER && self.prev_token == TokenType::NUMBER {
+            return self.error(CalcError::AdjacentNumbers);
+        }
+
+        // 统一推进索引（数字和标识符已经在上面移动到了末尾）
+        match self.current_token {
+            TokenType::NUMBER | TokenType::IDIV | TokenType::MOD | TokenType::IDENT |
+            TokenType::BOOL | TokenType::RESULTREF | TokenType::SUPERSCRIPT => {},
+            _ => self.current_index += 1,
         }
 
         self.debug(&format!("Token: {:?}", self.current_token));
+        Ok(())
     }
 
 
-    // <Expr> ::= <AddSubExpr>
-    fn eval_expr(&mut self) -> i32 {
-        self.debug("求值: 表达式");
-        self.eval_add_sub_expr()
+    // 每执行一次运算调用一次，超出上限时报错，防止失控的计算
+    fn count_op(&mut self) -> Result<(), EvalError> {
+        self.op_count += 1;
+        if let Some(limit) = self.max_ops {
+            if self.op_count > limit {
+                return self.error(CalcError::BudgetExceeded(limit));
+            }
+        }
+        Ok(())
     }
 
-    // <AddSubExpr> ::= <MulDivExpr> {('+' | '-') <MulDivExpr>}
-    fn eval_add_sub_expr(&mut self) -> i32 {
-        self.debug("求值: 加减表达式");
-
-        let mut result = self.eval_mul_div_expr();
+    // 进入一层嵌套，超过 --max-depth 时在左括号 (或负号、**) 处报错
+    fn open_nesting(&mut self, open_start: usize) -> Result<(), EvalError> {
+        self.nesting += 1;
+        self.max_nesting = self.max_nesting.max(self.nesting);
+        if self.nesting > self.depth_limit {
+            return self.error_at(open_start, CalcError::TooDeep(self.depth_limit));
+        }
+        Ok(())
+    }
 
-        while self.current_token == TokenType::ADD || self.current_token == TokenType::SUB {
-            let op_token = self.current_token; // 记录操作符
-            self.get_token();                  // 消耗操作符，获取下一个 Token
-            let temp_val = self.eval_mul_div_expr(); // 计算右侧表达式
+    // 对整个表达式求值，要求表达式之后没有多余字符
+    // 只返回结果或错误，不读写标准输入输出、也不退出进程；需要显示的内容放在 output 中
+    // <Line> ::= <Expr> ['=>' IDENT]
+    fn evaluate_checked(&mut self) -> Result<i128, EvalError> {
+        self.get_token()?; // 获取第一个 Token
+        if let Some(op) = binary_op_text(self.current_token) {
+            // 开头就是只能作二元运算符的符号 (如 *3、+-*/)，直接指出来，不必进入各层语法规则
+            // --implicit-ans 下改为用上一行的结果作左操作数，就像行首写了 @
+            match self.history.last() {
+                _ if self.current_token == TokenType::SUB => {},
+                Some(&last) if self.implicit_ans => self.pending_operand = Some(last),
+                _ => return self.error(CalcError::LeadingOperator(op)),
+            }
+        }
+        let expr_val = self.eval_expr()?;
 
-            match op_token {
-                TokenType::ADD => result += temp_val,
-                TokenType::SUB => result -= temp_val,
-                _ => {},
+        if self.current_token == TokenType::CAPTURE {
+            self.get_token()?; // 消耗 '=>'
+            match self.current_token {
+                TokenType::IDENT => {
+                    self.variables.insert(self.ident_val.clone(), expr_val);
+                    self.get_token()?; // 消耗变量名
+                },
+                TokenType::IDIV | TokenType::MOD | TokenType::BOOL => return self.error(CalcError::KeywordAsName(self.ident_val.clone())),
+                _ => return self.error(CalcError::BadCaptureName),
             }
         }
 
-        result
-    }
+        if self.current_token != TokenType::END {
+            // 列号从 1 开始，按字符计数；显示的是用户输入的那个 Token 的原文
+            let found = self.raw_chars[self.token_start..self.current_index].iter().collect();
+            return self.error(CalcError::TrailingChars { column: self.token_start + 1, found });
+        }
 
-    // <MulDivExpr> ::= <PrimaryExpr> {('*' | '/') <PrimaryExpr>}
-    fn eval_mul_div_expr(&mut self) -> i32 {
-        self.debug("求值: 乘除表达式");
+        Ok(expr_val)
+    }
 
-        let mut result = self.eval_primary_expr();
+    // 求值完成后决定结果用几进制输出
+    fn result_base(&self) -> u32 {
+        match self.output_base {
+            OutputBase::Fixed(base) => base,
+            OutputBase::Auto => match self.literal_bases.split_first() {
+                Some((&first, rest)) if rest.iter().all(|&b| b == first) => first,
+                _ => 10,
+            },
+        }
+    }
 
-        while self.current_token == TokenType::MUL || self.current_token == TokenType::DIV {
-            let op_token = self.current_token; // 记录操作符
-            self.get_token();                  // 消耗操作符，获取下一个 Token
-            let temp_val = self.eval_primary_expr(); // 计算右侧表达式
+    // 按当前的输出进制和分组设置格式化一个值，和命令行输出结果的写法完全一样
+    fn format_value(&self, value: i128) -> String {
+        match (self.result_base(), self.locale_separator) {
+            (10, Some(separator)) => format_decimal_grouped(value, separator),
+            (base, _) => format_in_base(value, base, self.group_base),
+        }
+    }
 
-            match op_token {
-                TokenType::MUL => result *= temp_val,
-                TokenType::DIV => {
-                    if temp_val == 0 {
-                        self.error("除零错误");
-                    }
-                    result /= temp_val;
-                },
-                _ => {},
-            }
+    // 当前宽度下能表示的最小值和最大值
+    fn bounds(&self) -> (i128, i128) {
+        if self.unsigned {
+            (0, (1i128 << self.width) - 1)
+        } else {
+            (-(1i128 << (self.width - 1)), (1i128 << (self.width - 1)) - 1)
         }
+    }
 
-        result
+    // 除数为 0 时按 --div-zero 处理，op 是 DIV、IDIV 或 MOD
+    fn divide_by_zero(&self, dividend: i128, op: TokenType, op_start: usize) -> Result<i128, EvalError> {
+        if self.div_zero == DivZeroPolicy::Error {
+            return self.error_at(op_start, CalcError::DivByZero);
+        }
+        let (min, max) = self.bounds();
+        Ok(match (op, dividend.signum()) {
+            (TokenType::MOD, _) | (_, 0) => dividend,
+            (_, 1) => max,
+            _ => min,
+        })
     }
 
-    // <PrimaryExpr> ::= NUM | '-'NUM | '(' <Expr> ')'
-    fn eval_primary_expr(&mut self) -> i32 {
-        self.debug("求值: 基本表达式");
+    // 把精确的运算结果约束到当前整数宽度：检查模式下超出范围就报错，
+    // 回绕模式下只保留低 width 位并按补码解释，例如 8 位时 200 + 100 == 44
+    // --unsigned 下范围是 0 到 2^width - 1，回绕时不做补码解释
+    fn fit(&self, value: i128, index: usize) -> Result<i128, EvalError> {
+        let (min, max) = self.bounds();
+        if (min..=max).contains(&value) {
+            return Ok(value);
+        }
+
+        match self.overflow {
+            OverflowMode::Checked => self.error_at(index, CalcError::Overflow),
+            OverflowMode::Wrapping => {
+                let low = value & ((1i128 << self.width) - 1);
+                let wrapped = if low > max { low - (1i128 << self.width) } else { low };
+                Ok(wrapped)
+            },
+        }
+    }
+
+    // 两个值的精确乘积。64 位无符号整数相乘可能超出 i128，这时报溢出，
+    // 供后面还要再除的 % 和 pctof、discount 使用，回绕模式也无法给出确定的结果
+    fn product(&self, a: i128, b: i128, index: usize) -> Result<i128, EvalError> {
+        match a.checked_mul(b) {
+            Some(v) => Ok(v),
+            None => self.error_at(index, CalcError::Overflow),
+        }
+    }
+
+    // a * b 约束到当前整数宽度；超出 i128 时在回绕模式下按 2^128 回绕，对 2^width 取模的结果不变
+    fn multiply(&self, a: i128, b: i128, index: usize) -> Result<i128, EvalError> {
+        match a.checked_mul(b) {
+            Some(v) => self.fit(v, index),
+            None if self.overflow == OverflowMode::Wrapping => self.fit(a.wrapping_mul(b), index),
+            None => self.error_at(index, CalcError::Overflow),
+        }
+    }
+
+    // 把一串数字字符按给定进制转换为数值，超过 LITERAL_MAX 时报错
+    fn parse_digits(&self, digits: &[char], radix: u32) -> Result<i128, EvalError> {
+        let mut value: i128 = 0;
+
+        for &c in digits {
+            // 将字符转换为数字并累加
+            let digit = c.to_digit(radix).unwrap();
+            value = value * radix as i128 + digit as i128;
+            if value > LITERAL_MAX {
+                return self.error(CalcError::Overflow);
+            }
+        }
+
+        Ok(value)
+    }
+
+    // 二元运算符之后必须是操作数。如果紧跟着另一个只能作二元运算符的符号 (如 2 * + 3)，
+    // 给出专门的提示；'-' 可以作一元负号，所以 2 * -3 是合法的
+    fn expect_operand(&self, op: TokenType) -> Result<(), EvalError> {
+        if let (Some(first), Some(second)) = (binary_op_text(op), binary_op_text(self.current_token)) {
+            if self.current_token != TokenType::SUB {
+                return self.error(CalcError::OperatorSequence(first, second));
+            }
+        }
+        Ok(())
+    }
+
+    // <Expr> ::= <BitXorExpr> {'|>' IDENT}
+    fn eval_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::Expr);
+        let mut result = self.eval_bit_xor_expr()?;
+
+        while self.current_token == TokenType::PIPE {
+            self.get_token()?; // 消耗 '|>'
+            if self.current_token != TokenType::IDENT {
+                return self.error(CalcError::BadPipeTarget);
+            }
+            let name = self.ident_val.clone();
+            let name_start = self.token_start;
+            self.get_token()?; // 消耗函数名
+            if name == "if" || name == "table" {
+                let expected = if name == "if" { 3 } else { 4 };
+                return self.error_at(name_start, CalcError::Arity { name, expected, got: 1 });
+            }
+            self.check_sandbox(&name, name_start)?;
+            result = self.eval_call(&name, name_start, Some(result))?;
+            self.comparison = false;
+        }
+
+        Ok(self.leave(event, result))
+    }
+
+    // <BitXorExpr> ::= <CompareExpr> {'^' <CompareExpr>}
+    fn eval_bit_xor_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::BitXorExpr);
+
+        let mut result = self.eval_compare_expr()?;
+
+        while self.current_token == TokenType::BITXOR {
+            self.get_token()?;                   // 消耗 '^'
+            self.expect_operand(TokenType::BITXOR)?;
+            let temp_val = self.eval_compare_expr()?; // 计算右侧表达式
+            self.count_op()?;
+            result ^= temp_val; // 两个操作数都在宽度范围内，异或结果也一定在范围内
+            self.comparison = false;
+        }
+
+        Ok(self.leave(event, result))
+    }
+
+    // <CompareExpr> ::= <AddSubExpr> {('<' | '<=' | '>' | '>=' | '==' | '!=') <AddSubExpr>}
+    // 连写的比较 a < b < c 按 a < b 并且 b < c 计算，中间的 b 只求值一次
+    fn eval_compare_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::CompareExpr);
+
+        let first = self.eval_add_sub_expr()?;
+        if !is_compare_op(self.current_token) {
+            return Ok(self.leave(event, first));
+        }
+
+        let mut left = first;
+        let mut all_true = true;
+
+        while is_compare_op(self.current_token) {
+            let op_token = self.current_token; // 记录操作符
+            self.get_token()?;                  // 消耗操作符，获取下一个 Token
+            self.expect_operand(op_token)?;
+            let right = self.eval_add_sub_expr()?; // 计算右侧表达式
+            self.count_op()?;
+
+            let holds = match op_token {
+                TokenType::LT => left < right,
+                TokenType::LE => left <= right,
+                TokenType::GT => left > right,
+                TokenType::GE => left >= right,
+                TokenType::EQ => left == right,
+                TokenType::NE => left != right,
+                _ => false,
+            };
+            all_true = all_true && holds;
+            left = right;
+        }
+        // 整个式子 (不算外面的分组括号) 是比较；外层再有别的运算时由那里清掉
+        self.comparison = true;
+
+        Ok(self.leave(event, all_true as i128))
+    }
+
+    // <AddSubExpr> ::= <MulDivExpr> {('+' | '-') <MulDivExpr>}
+    fn eval_add_sub_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::AddSubExpr);
+
+        let mut result = self.eval_mul_div_expr()?;
+        if self.current_token == TokenType::PERCENT {
+            return self.error(CalcError::StrayPercent);
+        }
+
+        while self.current_token == TokenType::ADD || self.current_token == TokenType::SUB {
+            let op_token = self.current_token; // 记录操作符
+            let op_start = self.token_start;   // 记录操作符位置，溢出时指向它
+            self.get_token()?;                  // 消耗操作符，获取下一个 Token
+            self.expect_operand(op_token)?;
+            let mut temp_val = self.eval_mul_div_expr()?; // 计算右侧表达式
+
+            if self.current_token == TokenType::PERCENT {
+                // 100 + 10%：右侧是左边结果的百分之几
+                let percent_start = self.token_start;
+                self.get_token()?; // 消耗 '%'
+                if self.current_token == TokenType::PERCENT {
+                    return self.error(CalcError::StrayPercent);
+                }
+                self.count_op()?;
+                temp_val = self.fit(self.rounding.divide(self.product(result, temp_val, percent_start)?, 100), percent_start)?;
+            }
+            self.count_op()?;
+            self.comparison = false;
+
+            match op_token {
+                TokenType::ADD => result = self.fit(result + temp_val, op_start)?,
+                TokenType::SUB => result = self.fit(result - temp_val, op_start)?,
+                _ => {},
+            }
+        }
+
+        Ok(self.leave(event, result))
+    }
+
+    // <MulDivExpr> ::= <UnaryExpr> {('*' | '/' | 'div' | 'mod') <UnaryExpr>}
+    fn eval_mul_div_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::MulDivExpr);
+
+        let mut result = self.eval_unary_expr()?;
+
+        while self.current_token == TokenType::MUL || self.current_token == TokenType::DIV ||
+            self.current_token == TokenType::IDIV || self.current_token == TokenType::MOD
+        {
+            let op_token = self.current_token; // 记录操作符
+            let op_start = self.token_start;   // 记录操作符位置，除零或溢出时指向它
+            self.get_token()?;                  // 消耗操作符，获取下一个 Token
+            self.expect_operand(op_token)?;
+            let temp_val = self.eval_unary_expr()?; // 计算右侧表达式
+            self.count_op()?;
+            self.comparison = false;
+
+            match op_token {
+                TokenType::MUL => result = self.multiply(result, temp_val, op_start)?,
+                TokenType::DIV => {
+                    if temp_val == 0 {
+                        result = self.divide_by_zero(result, op_token, op_start)?;
+                        continue;
+                    }
+                    let quot = self.fit(self.rounding.divide(result, temp_val), op_start)?;
+                    if self.warn_truncation && result % temp_val != 0 {
+                        // 余数按所选取整方式计算，满足 result == quot * temp_val + rem
+                        let rem = result - quot * temp_val;
+                        let truncated = self.rounding == RoundingMode::TruncZero;
+                        let note = match self.lang {
+                            Lang::Zh => format!("注意: {} / {} {}为 {} (余 {})",
+                                result, temp_val, if truncated { "截断" } else { "取整" }, quot, rem),
+                            Lang::En => format!("Note: {} / {} {} to {} (remainder {})",
+                                result, temp_val, if truncated { "truncated" } else { "rounded" }, quot, rem),
+                        };
+                        self.output.push(Output::Stderr(note));
+                    }
+                    result = quot;
+                },
+                TokenType::IDIV => {
+                    if temp_val == 0 {
+                        result = self.divide_by_zero(result, op_token, op_start)?;
+                        continue;
+                    }
+                    result = self.fit(result / temp_val, op_start)?;
+                },
+                TokenType::MOD => {
+                    if temp_val == 0 {
+                        result = self.divide_by_zero(result, op_token, op_start)?;
+                        continue;
+                    }
+                    result = self.fit(result % temp_val, op_start)?;
+                },
+                _ => {},
+            }
+        }
+
+        Ok(self.leave(event, result))
+    }
+
+    // <UnaryExpr> ::= '-' <UnaryExpr> | <PowExpr>
+    // 一元负号可以任意叠加，- - 2 == 2
+    fn eval_unary_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::UnaryExpr);
+
+        if self.current_token != TokenType::SUB {
+            let result = self.eval_pow_expr()?;
+            return Ok(self.leave(event, result));
+        }
+
+        let op_start = self.token_start;
+        self.get_token()?; // 消耗 '-'
+        self.expect_operand(TokenType::SUB)?;
+
+        // 负号后面紧跟的字面量正好是最小值的绝对值时 (如 -9223372036854775808，8 位下的 -128)，
+        // 这个正数本身超出范围，所以直接取负，不单独检查它
+        let min_magnitude = 1i128 << (self.width - 1);
+        if !self.unsigned && self.current_token == TokenType::NUMBER && self.number_val == min_magnitude {
+            let literal_start = self.token_start;
+            self.literal_bases.push(self.number_base);
+            self.get_token()?; // 消耗数字
+            if matches!(self.current_token, TokenType::EXP | TokenType::SUPERSCRIPT) {
+                // 乘方优先于负号，-128 ** 1 和 -128² 的底数仍是超出范围的 128
+                return self.error_at(literal_start, CalcError::Overflow);
+            }
+            self.count_op()?;
+            let result = self.fit(-min_magnitude, op_start)?;
+            return Ok(self.leave(event, result));
+        }
+
+        // - - - 1 每个负号递归一层，和括号一样计入嵌套层数
+        self.open_nesting(op_start)?;
+        let val = self.eval_unary_expr()?;
+        self.nesting -= 1;
+        self.count_op()?;
+        self.comparison = false;
+
+        let result = self.fit(-val, op_start)?;
+        Ok(self.leave(event, result))
+    }
+
+    // <PowExpr> ::= <PrimaryExpr> ['**' <UnaryExpr>]
+    // 乘方是右结合的：2 ** 3 ** 2 == 2 ** 9；指数可以带负号，2 ** -1 会报负指数错误
+    fn eval_pow_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::PowExpr);
+
+        let mut base = self.eval_primary_expr()?;
+
+        if self.current_token == TokenType::SUPERSCRIPT {
+            // 上标指数直接作用于底数，比 ** 结合得更紧
+            let op_start = self.token_start;
+            let exponent = self.number_val;
+            self.get_token()?; // 消耗上标
+            self.count_op()?;
+            self.comparison = false;
+            base = self.power(base, exponent, op_start)?;
+        }
+
+        if self.current_token != TokenType::EXP {
+            return Ok(self.leave(event, base));
+        }
+
+        let op_start = self.token_start;
+        self.get_token()?; // 消耗 '**'
+        self.expect_operand(TokenType::EXP)?;
+        self.open_nesting(op_start)?;
+        let exponent = self.eval_unary_expr()?; // 递归实现右结合，每个 ** 计入一层嵌套
+        self.nesting -= 1;
+        self.count_op()?;
+        self.comparison = false;
+
+        let result = self.power(base, exponent, op_start)?;
+        Ok(self.leave(event, result))
+    }
+
+    // 计算 base 的 exponent 次方，op_start 是报错时指向的运算符位置
+    fn power(&self, base: i128, exponent: i128, op_start: usize) -> Result<i128, EvalError> {
+        if exponent < 0 {
+            return self.error_at(op_start, CalcError::NegativeExponent);
+        }
+        let exponent = match u32::try_from(exponent) {
+            Ok(e) => e,
+            Err(_) => return self.error_at(op_start, CalcError::Overflow),
+        };
+        // 回绕模式下在 128 位上回绕再截断到 width 位，两者对 2^width 取模的结果相同
+        match base.checked_pow(exponent) {
+            Some(v) => self.fit(v, op_start),
+            None if self.overflow == OverflowMode::Wrapping => self.fit(base.wrapping_pow(exponent), op_start),
+            None => self.error_at(op_start, CalcError::Overflow),
+        }
+    }
+
+    // 当前 Token 是哪一种括号
+    fn bracket(&self) -> char {
+        self.src_chars[self.token_start]
+    }
+
+    // 要求当前 Token 是与 open_start 处的左括号同类的右括号，调用方随后消耗它
+    fn expect_close(&self, open_start: usize) -> Result<(), EvalError> {
+        let open = self.src_chars[open_start];
+        if self.current_token != TokenType::RIGHTPAREN {
+            return self.error(CalcError::MissingRightParen(closing_bracket(open)));
+        }
+        if self.bracket() != closing_bracket(open) {
+            return self.error(CalcError::BracketMismatch { open, open_column: open_start + 1, found: self.bracket() });
+        }
+        Ok(())
+    }
+
+    // 读取函数调用的参数列表 '(' [<Expr> {',' <Expr>}] ')'，调用时当前 Token 是 '('
+    fn eval_args(&mut self) -> Result<Vec<i128>, EvalError> {
+        let mut args = Vec::new();
+        let open_start = self.token_start;
+        self.get_token()?; // 消耗 '('
+
+        if self.current_token != TokenType::RIGHTPAREN {
+            args.push(self.eval_expr()?);
+            while self.current_token == TokenType::COMMA {
+                self.get_token()?; // 消耗 ','
+                args.push(self.eval_expr()?);
+            }
+        }
+
+        self.expect_close(open_start)?;
+        self.get_token()?; // 消耗 ')'
+
+        Ok(args)
+    }
+
+    // 检查参数个数
+    fn check_arity(&self, name: &str, args: &[i128], expected: usize, name_start: usize) -> Result<(), EvalError> {
+        if args.len() != expected {
+            return self.error_at(name_start, CalcError::Arity {
+                name: name.to_string(),
+                expected,
+                got: args.len(),
+            });
+        }
+        Ok(())
+    }
+
+    // 跳过一个不求值的参数：扫描到同一层的 ',' 或 ')' 为止，括号必须配对
+    fn skip_arg(&mut self) -> Result<(), EvalError> {
+        match self.current_token {
+            TokenType::END => return self.error(CalcError::UnexpectedEof),
+            TokenType::COMMA | TokenType::RIGHTPAREN => return self.error(CalcError::BadPrimary),
+            _ => {},
+        }
+
+        let mut depth = 0;
+        loop {
+            match self.current_token {
+                TokenType::COMMA | TokenType::RIGHTPAREN if depth == 0 => return Ok(()),
+                TokenType::LEFTPAREN => depth += 1,
+                TokenType::RIGHTPAREN => depth -= 1,
+                TokenType::END => return Ok(()), // 由调用方报告缺少 ')'
+                _ => {},
+            }
+            self.get_token()?;
+        }
+    }
+
+    // 'if' '(' <Expr> ',' <Expr> ',' <Expr> ')'，调用时当前 Token 是 '('
+    // 和普通函数不同，两个分支只求值选中的那一个
+    fn eval_if(&mut self, name_start: usize) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::Call);
+        let open_start = self.token_start;
+        self.get_token()?; // 消耗 '('
+
+        let mut got = 0;
+        let mut result = 0;
+        let mut cond = true;
+        while self.current_token != TokenType::RIGHTPAREN && self.current_token != TokenType::END {
+            if got > 0 {
+                if self.current_token != TokenType::COMMA {
+                    return self.error(CalcError::MissingRightParen(')'));
+                }
+                self.get_token()?; // 消耗 ','
+            }
+            // 第 0 个参数是条件，第 1、2 个是分支，多出来的参数只计数
+            match got {
+                0 => cond = self.eval_expr()? != 0,
+                1 | 2 if cond == (got == 1) => result = self.eval_expr()?,
+                _ => self.skip_arg()?,
+            }
+            got += 1;
+        }
+
+        self.expect_close(open_start)?;
+        if got != 3 {
+            return self.error_at(name_start, CalcError::Arity { name: "if".to_string(), expected: 3, got });
+        }
+        self.get_token()?; // 消耗 ')'
+
+        Ok(self.leave(event, result))
+    }
+
+    // 沙箱模式下在求值参数之前拒绝有副作用的函数，函数调用和 '|>' 都经过这里
+    fn check_sandbox(&self, name: &str, name_start: usize) -> Result<(), EvalError> {
+        if self.sandbox && has_side_effects(name) {
+            return self.error_at(name_start, CalcError::DisabledInSandbox(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // 'table' '(' <Expr> ',' IDENT ',' <Expr> ',' <Expr> [',' <Expr>] ')'，调用时当前 Token 是 '('
+    // 先跳过 body 读出其余参数，再对每个 x 回到 body 开头重新求值
+    fn eval_table(&mut self, name_start: usize) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::Call);
+        let open_start = self.token_start;
+        self.get_token()?; // 消耗 '('
+
+        let body_start = self.token_start;
+        self.skip_arg()?;
+        let mut got = 1;
+
+        let var = if self.current_token == TokenType::COMMA {
+            self.get_token()?; // 消耗 ','
+            if self.current_token != TokenType::IDENT {
+                return self.error(CalcError::BadLoopVar);
+            }
+            let var = self.ident_val.clone();
+            self.get_token()?; // 消耗变量名
+            got += 1;
+            var
+        } else {
+            String::new()
+        };
+
+        // lo、hi 和可选的 step
+        let mut bounds = Vec::new();
+        while got > 1 && self.current_token == TokenType::COMMA {
+            self.get_token()?; // 消耗 ','
+            bounds.push(self.eval_expr()?);
+            got += 1;
+        }
+        self.expect_close(open_start)?;
+        if got != 4 && got != 5 {
+            return self.error_at(name_start, CalcError::Arity { name: "table".to_string(), expected: 4, got });
+        }
+        let step = bounds.get(2).copied().unwrap_or(1);
+        if step <= 0 {
+            return self.error_at(name_start, CalcError::BadStep(step));
+        }
+        let close_start = self.token_start;
+
+        let (lo, hi) = (bounds[0], bounds[1]);
+        // 每一行都要留到整行求值结束才输出，行数太多时先报错，而不是等内存耗尽
+        if lo <= hi && (hi - lo) / step >= MAX_TABLE_ROWS as i128 {
+            return self.error_at(name_start, CalcError::TooManyRows(MAX_TABLE_ROWS));
+        }
+
+        let saved = self.variables.get(&var).copied();
+        if lo > hi {
+            // 范围为空时 body 也按 x = lo 求值一次，只为检查写法：不输出，除零这类求值错误也不报
+            let (output_len, nesting) = (self.output.len(), self.nesting);
+            match self.eval_table_body(&var, lo, body_start) {
+                Err(error) if error.err.is_syntax() => return Err(error),
+                _ => {},
+            }
+            self.output.truncate(output_len);
+            self.nesting = nesting;
+        }
+        let mut rows = 0;
+        let mut x = lo;
+        while x <= hi {
+            self.count_op()?;
+            let value = self.eval_table_body(&var, x, body_start)?;
+
+            let line = format!("{}\t{}", self.format_value(x), self.format_value(value));
+            self.output.push(Output::Stdout(line));
+            rows += 1;
+            x += step;
+        }
+        match saved {
+            Some(value) => self.variables.insert(var, value),
+            None => self.variables.remove(&var),
+        };
+
+        // 回到 ')' 处继续
+        self.current_index = close_start;
+        self.get_token()?;
+        self.get_token()?; // 消耗 ')'
+
+        // 行数和其他结果一样受整数宽度限制
+        let result = self.fit(rows as i128, name_start)?;
+        Ok(self.leave(event, result))
+    }
+
+    // 让 var 取值 x，回到 body 开头求值一次，body 之后必须是 ','
+    fn eval_table_body(&mut self, var: &str, x: i128, body_start: usize) -> Result<i128, EvalError> {
+        self.variables.insert(var.to_string(), x);
+        self.current_index = body_start;
+        self.current_token = TokenType::COMMA;
+        self.get_token()?;
+        let value = self.eval_expr()?;
+        if self.current_token != TokenType::COMMA {
+            return self.error(CalcError::MissingRightParen(')'));
+        }
+        Ok(value)
+    }
+
+    // <Call> ::= IDENT '(' [<Expr> {',' <Expr>}] ')'
+    // piped 是 x |> f 中左边的值，这时没有括号，它就是唯一的参数
+    fn eval_call(&mut self, name: &str, name_start: usize, piped: Option<i128>) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::Call);
+
+        let args = match piped {
+            Some(value) => vec![value],
+            None => self.eval_args()?,
+        };
+
+        let result = match name {
+            "divmod" => {
+                // divmod(a, b) 同时输出商和余数，表达式的值取商
+                self.check_arity(name, &args, 2, name_start)?;
+                let (a, b) = (args[0], args[1]);
+                if b == 0 {
+                    return self.error_at(name_start, CalcError::DivByZero);
+                }
+                self.count_op()?;
+                let quot = self.fit(a / b, name_start)?;
+                let rem = a % b;
+
+                let line = match self.lang {
+                    Lang::Zh => format!("{} divmod {} => {} 余 {}", a, b, quot, rem),
+                    Lang::En => format!("{} divmod {} => {} remainder {}", a, b, quot, rem),
+                };
+                self.output.push(Output::Stdout(line));
+                quot
+            },
+            "root" => {
+                // root(n, x) 求 x 的 n 次整数方根，结果向零取整：root(3, 27) == 3，root(2, 17) == 4
+                self.check_arity(name, &args, 2, name_start)?;
+                let (n, x) = (args[0], args[1]);
+                if n <= 0 {
+                    return self.error_at(name_start, CalcError::BadRootDegree);
+                }
+                if x < 0 && n % 2 == 0 {
+                    return self.error_at(name_start, CalcError::RootOfNegative);
+                }
+                self.count_op()?;
+
+                // 奇次方根对负数取实根：root(3, -27) == -3
+                let magnitude = integer_root(x.unsigned_abs() as u64, n as u64) as i128;
+                self.fit(if x < 0 { -magnitude } else { magnitude }, name_start)?
+            },
+            "powmod" => {
+                // powmod(b, e, m) 求 b 的 e 次方除以 m 的余数，用平方乘算法，指数很大也不会溢出：powmod(7, 128, 13) == 3
+                // 结果总在 0 到 |m| - 1 之间；m == 0 报错，负指数 (需要模逆元) 不支持
+                self.check_arity(name, &args, 3, name_start)?;
+                let (b, e, m) = (args[0], args[1], args[2]);
+                if m == 0 {
+                    return self.error_at(name_start, CalcError::DivByZero);
+                }
+                if e < 0 {
+                    return self.error_at(name_start, CalcError::NegativeExponent);
+                }
+                self.count_op()?;
+
+                // 中间结果小于 m²，在 u128 中不会溢出
+                let modulus = m.unsigned_abs();
+                let mut base = b.rem_euclid(modulus as i128) as u128;
+                let mut exp = e as u64;
+                let mut acc = 1 % modulus;
+                while exp > 0 {
+                    if exp & 1 == 1 {
+                        acc = acc * base % modulus;
+                    }
+                    base = base * base % modulus;
+                    exp >>= 1;
+                }
+                self.fit(acc as i128, name_start)?
+            },
+            "pctof" | "discount" => {
+                // pctof(p, x) 是 x 的 p%：pctof(20, 50) == 10
+                // discount(x, p) 是 x 打掉 p% 之后的值：discount(100, 25) == 75
+                // 结果不是整数时和 '/' 一样按当前取整方式处理
+                self.check_arity(name, &args, 2, name_start)?;
+                let (numerator, denominator) = if name == "pctof" {
+                    (self.product(args[0], args[1], name_start)?, 100)
+                } else {
+                    (self.product(args[0], 100 - args[1], name_start)?, 100)
+                };
+                self.count_op()?;
+
+                self.fit(self.rounding.divide(numerator, denominator), name_start)?
+            },
+            "sum" | "avg" => {
+                // sum(1, 2, 3, 4) == 10，没有参数时为 0
+                // avg(2, 4, 6) == 4，平均值不是整数时按当前取整方式处理；avg() 没有意义，报错
+                if name == "avg" && args.is_empty() {
+                    return self.error_at(name_start, CalcError::TooFewArgs { name: name.to_string(), min: 1, got: 0 });
+                }
+                let mut total: i128 = 0;
+                for (i, &arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.count_op()?;
+                    }
+                    total += arg;
+                }
+
+                if name == "sum" {
+                    self.fit(total, name_start)?
+                } else {
+                    // 总和可能超出整数宽度，但平均值一定在范围内
+                    self.count_op()?;
+                    self.fit(self.rounding.divide(total, args.len() as i128), name_start)?
+                }
+            },
+            "gcd" | "lcm" => {
+                // 两两折叠：gcd(12, 18, 24) == 6，lcm(4, 6, 10) == 60；结果总是非负数
+                // 只有一个参数时就是它的绝对值；任一参数为 0 时 lcm 为 0
+                if args.is_empty() {
+                    return self.error_at(name_start, CalcError::TooFewArgs { name: name.to_string(), min: 1, got: 0 });
+                }
+                let mut acc = args[0].unsigned_abs();
+                for &arg in &args[1..] {
+                    self.count_op()?;
+                    let x = arg.unsigned_abs();
+                    let g = gcd(acc, x);
+                    acc = if name == "gcd" {
+                        g
+                    } else {
+                        // 每次折叠之后都检查范围，避免中间结果越滚越大；g 为 0 说明两个数都是 0
+                        // 回绕之后的值不再是公倍数，所以 lcm 超出范围时总是报错，不按 --overflow wrap 回绕
+                        let lcm = acc.checked_div(g).map_or(0, |q| q * x);
+                        if lcm > self.bounds().1 as u128 {
+                            return self.error_at(name_start, CalcError::Overflow);
+                        }
+                        lcm
+                    };
+                }
+                self.fit(acc as i128, name_start)?
+            },
+            "tobase" => {
+                // tobase(x, b) 输出 x 的 b 进制写法 (不带前缀，字母小写)，表达式的值仍是 x：tobase(255, 16) 输出 ff
+                self.check_arity(name, &args, 2, name_start)?;
+                let (x, base) = (args[0], args[1]);
+                if !(2..=36).contains(&base) {
+                    return self.error_at(name_start, CalcError::BadBase(base));
+                }
+
+                let line = format!("{} tobase {} => {}", x, base, to_radix(x, base as u32));
+                self.output.push(Output::Stdout(line));
+                x
+            },
+            "popcount" | "leading_zeros" | "trailing_zeros" | "bit_reverse" => {
+                // 按当前整数宽度的补码计算，负数的高位都是 1：32 位下 popcount(-1) == 32
+                // popcount(7) == 3，trailing_zeros(8) == 3，32 位下 leading_zeros(1) == 31
+                // 0 的 leading_zeros / trailing_zeros 等于整数宽度
+                self.check_arity(name, &args, 1, name_start)?;
+                let bits = to_bits(args[0], self.width);
+                let unused = 64 - self.width; // u64 中不属于当前宽度的高位个数
+                self.count_op()?;
+
+                match name {
+                    "popcount" => bits.count_ones() as i128,
+                    "leading_zeros" => (bits.leading_zeros() - unused) as i128,
+                    "trailing_zeros" => bits.trailing_zeros().min(self.width) as i128,
+                    _ if self.unsigned => (bits.reverse_bits() >> unused) as i128,
+                    _ => from_bits(bits.reverse_bits() >> unused, self.width),
+                }
+            },
+            _ => return self.error_at(name_start, CalcError::UnknownFunction(name.to_string())),
+        };
+        Ok(self.leave(event, result))
+    }
+
+    // <PrimaryExpr> ::= NUM | '(' <Expr> ')' | <Call>
+    fn eval_primary_expr(&mut self) -> Result<i128, EvalError> {
+        let event = self.enter(Rule::PrimaryExpr);
+        // 只有括号里的比较会把 comparison 重新置上，数字、变量、函数调用都不是比较
+        self.comparison = false;
+        if let Some(val) = self.pending_operand.take() {
+            return Ok(self.leave(event, val));
+        }
 
         let result = match self.current_token {
             TokenType::NUMBER => {
-                let val = self.number_val;
-                self.get_token(); // 消耗数字
+                self.literal_bases.push(self.number_base);
+                let val = self.fit(self.number_val, self.token_start)?;
+                self.get_token()?; // 消耗数字
                 val
             },
-            TokenType::SUB => { // 识别为一元负号
-                self.get_token(); // 消耗 '-'
-                if self.current_token == TokenType::NUMBER {
-                    let val = -self.number_val;
-                    self.get_token(); // 消耗数字
-                    val
-                } else if self.current_token == TokenType::LEFTPAREN {
-                    // 支持 -(Expr) 格式
-                    let val = self.eval_primary_expr();
-                    -val
-                } else {
-                    self.error("一元负号后必须跟数字或带括号的表达式");
-                }
-            }
             TokenType::LEFTPAREN => {
-                self.get_token(); // 消耗 '('
-                let val = self.eval_expr();
-                if self.current_token != TokenType::RIGHTPAREN {
-                    self.error("缺少右括号 ')'");
+                let open_start = self.token_start;
+                self.open_nesting(open_start)?;
+                self.get_token()?; // 消耗左括号
+                let val = self.eval_expr()?;
+                self.expect_close(open_start)?;
+                self.get_token()?; // 消耗右括号
+                self.nesting -= 1;
+                val
+            },
+            TokenType::BOOL => {
+                // true 为 1，false 为 0
+                let val = self.number_val;
+                self.get_token()?; // 消耗 true / false
+                val
+            },
+            TokenType::RESULTREF => {
+                // $1 是第一个结果，超出范围时报错
+                let index = self.number_val as usize;
+                let val = match index.checked_sub(1).and_then(|i| self.history.get(i)) {
+                    Some(&v) => v,
+                    None => return self.error(CalcError::BadResultRef { index, count: self.history.len() }),
+                };
+                self.get_token()?; // 消耗 $N
+                val
+            },
+            TokenType::LASTRESULT => {
+                let val = match self.history.last() {
+                    Some(&v) => v,
+                    None => return self.error(CalcError::NoPreviousResult),
+                };
+                self.get_token()?; // 消耗 @
+                val
+            },
+            TokenType::IDIV | TokenType::MOD => {
+                // div / mod 只能出现在两个操作数之间
+                return self.error(CalcError::KeywordAsName(self.ident_val.clone()));
+            },
+            TokenType::IDENT => {
+                let name = self.ident_val.clone();
+                let name_start = self.token_start;
+                self.get_token()?; // 消耗标识符
+
+                if self.current_token != TokenType::LEFTPAREN || self.bracket() != '(' {
+                    // 不是函数调用，就是之前用 => 保存的变量
+                    return match self.variables.get(&name) {
+                        Some(&val) => Ok(self.leave(event, val)),
+                        None => self.error_at(name_start, CalcError::UnknownIdent(name)),
+                    };
                 }
-                self.get_token(); // 消耗 ')'
+                self.check_sandbox(&name, name_start)?;
+                self.open_nesting(self.token_start)?;
+                let val = if name == "if" {
+                    self.eval_if(name_start)?
+                } else if name == "table" {
+                    self.eval_table(name_start)?
+                } else {
+                    self.eval_call(&name, name_start, None)?
+                };
+                self.comparison = false; // 参数中的比较不算，if(1 < 2, 0, 1) 不是比较
+                self.nesting -= 1;
                 val
             },
-            _ => self.error("非法基本表达式起始 (期望数字、'-' 或 '(')"),
+            TokenType::END => return self.error(CalcError::UnexpectedEof),
+            _ => return self.error(CalcError::BadPrimary),
         };
 
-        result
+        Ok(self.leave(event, result))
     }
 }
 
-fn main() {
-    print!("写下你想计算的算式: ");
-    // 确保提示立即显示
-    io::stdout().flush().unwrap();
+// 字符在终端中占用的显示列数：东亚宽字符（中文、全角符号等）占两列，其余占一列
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115F |   // 谚文字母
+        0x2E80..=0x303E |   // CJK 部首、标点
+        0x3041..=0x33FF |   // 假名、CJK 符号
+        0x3400..=0x4DBF |   // CJK 扩展 A
+        0x4E00..=0x9FFF |   // CJK 统一汉字
+        0xA000..=0xA4CF |   // 彝文
+        0xAC00..=0xD7A3 |   // 谚文音节
+        0xF900..=0xFAFF |   // CJK 兼容汉字
+        0xFE30..=0xFE4F |   // CJK 兼容形式
+        0xFF00..=0xFF60 |   // 全角 ASCII
+        0xFFE0..=0xFFE6 |   // 全角符号
+        0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+// 求 x 的 n 次整数方根 (不超过真实方根的最大整数)，n >= 1
+fn integer_root(x: u64, n: u64) -> u64 {
+    // x < 2^64，所以 n >= 64 时方根只能是 0 或 1，把 n 限制在 64 结果不变
+    let n = n.min(64) as u32;
+    // 二分查找满足 r^n <= x 的最大 r
+    let (mut low, mut high) = (0u64, x);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        let fits = (mid as u128).checked_pow(n).is_some_and(|p| p <= x as u128);
+        if fits {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+// 按进制格式化整数，非十进制带 0b / 0o / 0x 前缀，负数的负号写在前缀前面：-0x10
+// grouped 为 true 时从低位起用 '_' 分组：二进制每 4 位、八进制每 3 位、十六进制每 2 位 (一个字节)
+fn format_in_base(value: i128, base: u32, grouped: bool) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+
+    let (prefix, digits, group) = match base {
+        2 => ("0b", format!("{:b}", magnitude), 4),
+        8 => ("0o", format!("{:o}", magnitude), 3),
+        16 => ("0x", format!("{:x}", magnitude), 2),
+        _ => return value.to_string(),
+    };
+    if !grouped {
+        return format!("{}{}{}", sign, prefix, digits);
+    }
+
+    format!("{}{}{}", sign, prefix, group_digits(&digits, group, '_'))
+}
+
+// 十进制整数按地区的千位分隔符分组：1000000 在 de-DE 下是 1.000.000
+fn format_decimal_grouped(value: i128, separator: char) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    format!("{}{}", sign, group_digits(&value.unsigned_abs().to_string(), 3, separator))
+}
 
-    let mut buf = String::new();
-    io::stdin().read_line(&mut buf).unwrap();
-    let src = buf.trim().to_string();
+// 从低位起每 group 位插入一个分隔符，最高的一组可以不满
+fn group_digits(digits: &str, group: usize, separator: char) -> String {
+    let digits: Vec<char> = digits.chars().collect();
+    let first = match digits.len() % group {
+        0 => group,
+        n => n,
+    };
+    let mut groups = vec![digits[..first].iter().collect::<String>()];
+    groups.extend(digits[first..].chunks(group).map(|chunk| chunk.iter().collect::<String>()));
+    groups.join(&separator.to_string())
+}
 
-    // 初始化解析器
-    // 启用 调试 模式
-    let mut calculator = Calculator::new(src, true);
+// --locale 支持的地区及其千位分隔符；结果都是整数，用不到小数点
+const LOCALES: [(&str, char); 7] = [
+    ("en-US", ','),
+    ("zh-CN", ','),
+    ("ja-JP", ','),
+    ("de-DE", '.'),
+    ("it-IT", '.'),
+    ("fr-FR", ' '),
+    ("de-CH", '\''),
+];
 
-    // 开始解析
-    calculator.get_token(); // 获取第一个 Token
-    let expr_val = calculator.eval_expr();
+// 除了返回值还会产生输出的函数，沙箱模式下禁用
+fn has_side_effects(name: &str) -> bool {
+    matches!(name, "divmod" | "tobase" | "table")
+}
 
-    if calculator.current_token != TokenType::END {
-        calculator.error("表达式后存在多余字符");
+// 按任意进制 (2 到 36) 写出整数，不带前缀，负数前面加 '-'
+fn to_radix(value: i128, base: u32) -> String {
+    let mut magnitude = value.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(char::from_digit((magnitude % base as u128) as u32, base).unwrap());
+        magnitude /= base as u128;
+        if magnitude == 0 {
+            break;
+        }
     }
+    if value < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+// 辗转相除求最大公约数，gcd(0, 0) == 0
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+// 取值的低 width 位 (补码)，例如 8 位时 -1 是 0xff
+fn to_bits(value: i128, width: u32) -> u64 {
+    (value as u64) & (u64::MAX >> (64 - width))
+}
+
+// to_bits 的逆运算：把低 width 位按补码解释为有符号数，例如 8 位时 0x80 是 -128
+fn from_bits(bits: u64, width: u32) -> i128 {
+    (((bits << (64 - width)) as i64) >> (64 - width)) as i128
+}
+
+// 上标数字 ⁰¹²³⁴⁵⁶⁷⁸⁹ 对应的普通数字
+fn superscript_digit(c: char) -> Option<char> {
+    match c {
+        '⁰' => Some('0'),
+        '¹' => Some('1'),
+        '²' => Some('2'),
+        '³' => Some('3'),
+        '⁴'..='⁹' => char::from_digit(c as u32 - '⁰' as u32, 10),
+        _ => None,
+    }
+}
+
+// 与左括号配对的右括号
+fn closing_bracket(open: char) -> char {
+    match open {
+        '[' => ']',
+        '{' => '}',
+        _ => ')',
+    }
+}
+
+// 两个字符串之间的编辑距离 (插入、删除、替换各算一步)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = (ca != cb) as usize;
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+// 是否是比较运算符
+fn is_compare_op(token: TokenType) -> bool {
+    matches!(token, TokenType::LT | TokenType::LE | TokenType::GT |
+                    TokenType::GE | TokenType::EQ | TokenType::NE)
+}
+
+// 二元运算符在源码中的写法，不是二元运算符时返回 None
+fn binary_op_text(token: TokenType) -> Option<&'static str> {
+    match token {
+        TokenType::ADD => Some("+"),
+        TokenType::SUB => Some("-"),
+        TokenType::MUL => Some("*"),
+        TokenType::DIV => Some("/"),
+        TokenType::IDIV => Some("div"),
+        TokenType::MOD => Some("mod"),
+        TokenType::EXP => Some("**"),
+        TokenType::BITXOR => Some("^"),
+        TokenType::LT => Some("<"),
+        TokenType::LE => Some("<="),
+        TokenType::GT => Some(">"),
+        TokenType::GE => Some(">="),
+        TokenType::EQ => Some("=="),
+        TokenType::NE => Some("!="),
+        _ => None,
+    }
+}
 
-    println!("结果是: {}", expr_val);
+// 识别 0x / 0b 进制前缀，前缀后面必须全是该进制的数字
+fn split_base_prefix(word: &[char]) -> Option<(&[char], u32)> {
+    if word.len() < 3 || word[0] != '0' {
+        return None;
+    }
+
+    let radix = match word[1] {
+        'x' | 'X' => 16,
+        'b' | 'B' => 2,
+        _ => return None,
+    };
+    let digits = &word[2..];
+
+    if digits.iter().all(|c| c.is_digit(radix)) {
+        Some((digits, radix))
+    } else {
+        None
+    }
+}
+
+// 识别数量级后缀：k/K 表示千，M 表示百万，G 表示十亿。
+// 后缀必须是数字后面唯一的一个字母，像 3km 这样后面还有字母的不算
+fn split_scale_suffix(word: &[char]) -> Option<(&[char], i64)> {
+    let (&suffix, digits) = word.split_last()?;
+    if digits.is_empty() || !digits.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    match suffix {
+        'k' | 'K' => Some((digits, 1_000)),
+        'M' => Some((digits, 1_000_000)),
+        'G' => Some((digits, 1_000_000_000)),
+        _ => None,
+    }
+}
+
+// 开头是用逗号分组的十进制数 (如 1,000,000) 时返回它占的字符数，至少要有一个逗号
+// 第一组 1 到 3 位，之后每组正好 3 位，最后一组后面不能紧跟字母、数字或下划线
+fn thousands_group_len(chars: &[char]) -> Option<usize> {
+    let lead = chars.iter().take_while(|c| c.is_ascii_digit()).count();
+    if !(1..=3).contains(&lead) {
+        return None;
+    }
+
+    let mut len = lead;
+    while chars.get(len) == Some(&',') &&
+        chars.len() >= len + 4 &&
+        chars[len + 1..len + 4].iter().all(|c| c.is_ascii_digit())
+    {
+        len += 4;
+    }
+
+    let followed_by_word = chars.get(len).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_');
+    if len == lead || followed_by_word {
+        return None;
+    }
+    Some(len)
+}
+
+// 识别时间单位后缀 h / m / s，返回数字部分和一个单位是多少秒
+fn split_time_unit(word: &[char]) -> Option<(&[char], i64)> {
+    let (&suffix, digits) = word.split_last()?;
+    if digits.is_empty() || !digits.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    match suffix {
+        'h' => Some((digits, 3600)),
+        'm' => Some((digits, 60)),
+        's' => Some((digits, 1)),
+        _ => None,
+    }
 }
 
+// 识别科学计数法 <数字>e<数字>，返回底数和指数两部分
+fn split_exponent(word: &[char]) -> Option<(&[char], &[char])> {
+    let e_index = word.iter().position(|&c| c == 'e' || c == 'E')?;
+    let (mantissa, exponent) = (&word[..e_index], &word[e_index + 1..]);
+
+    if !mantissa.is_empty() && !exponent.is_empty() &&
+        mantissa.iter().all(|c| c.is_ascii_digit()) &&
+        exponent.iter().all(|c| c.is_ascii_digit())
+    {
+        Some((mantissa, exponent))
+    } else {
+        None
+    }
+}
+
+// 识别汇编风格的进制后缀：以 h 结尾且其余都是十六进制数字的为十六进制 (FFh)，
+// 以 b 结尾且其余都是 0/1 并以数字开头的为二进制 (1010b)。
+// word 是完整的字母数字串，所以后缀后面紧跟字母或数字 (如 10bx) 时不会被当成后缀。
+// 注意 FFh、beefh 这类全由十六进制字母组成的词会被当成数字而不是标识符。
+fn split_base_suffix(word: &[char]) -> Option<(&[char], u32)> {
+    let (&suffix, digits) = word.split_last()?;
+    if digits.is_empty() {
+        return None;
+    }
+
+    match suffix {
+        'h' | 'H' if digits.iter().all(|c| c.is_ascii_hexdigit()) => Some((digits, 16)),
+        'b' | 'B' if digits[0].is_ascii_digit() && digits.iter().all(|&c| c == '0' || c == '1') => Some((digits, 2)),
+        _ => None,
+    }
+}
+
+// 全角字符转半角：全角 ASCII 区 (U+FF01..U+FF5E) 与 ASCII 一一对应，全角空格转为普通空格
+// 转换前后字符个数不变，所以 src_chars 和 raw_chars 的下标可以通用
+fn to_halfwidth(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap(),
+        '\u{3000}' => ' ',
+        _ => c,
+    }
+}
+
+// 整理读入的原始文本：去掉 Windows 记事本等工具写入的 UTF-8 BOM，
+// 统一 \r\n 换行，再去掉首尾空白
+fn clean_input(buf: &str) -> String {
+    let buf = buf.strip_prefix('\u{FEFF}').unwrap_or(buf);
+    buf.replace("\r\n", "\n").trim().to_string()
+}
+
+// 命令行用法错误，直接退出
+fn usage_error(lang: Lang, message: &str) -> ! {
+    eprintln!("{}: {}", lang.error_label(), message);
+    process::exit(1);
+}
+
+// 当前构建支持的数值类型、函数和模式，--help 据此列出
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Capabilities {
+    int: bool,      // 定宽整数 (最多 64 位，有符号或无符号)
+    float: bool,    // 浮点数
+    rational: bool, // 有理数
+    bigint: bool,   // 任意精度整数
+    functions: &'static [&'static str],
+    widths: &'static [u32],
+    rounding_modes: &'static [&'static str],
+    overflow_modes: &'static [&'static str],
+    output_bases: &'static [&'static str],
+}
+
+// 增加函数或模式时同步修改这里
+fn capabilities() -> Capabilities {
+    Capabilities {
+        int: true,
+        float: false,
+        rational: false,
+        bigint: false,
+        functions: &[
+            "if", "divmod", "root", "powmod", "pctof", "discount", "sum", "avg", "gcd", "lcm", "tobase", "table",
+            "popcount", "leading_zeros", "trailing_zeros", "bit_reverse",
+        ],
+        widths: &[8, 16, 32, 64],
+        rounding_modes: &["trunc", "floor", "nearest", "ceil", "half-even"],
+        overflow_modes: &["checked", "wrap"],
+        output_bases: &["2", "8", "10", "16", "auto"],
+    }
+}
+
+// 一个命令行选项的说明，--help 从这里生成
+struct FlagInfo {
+    usage: &'static str, // 选项及其参数的写法
+    zh: &'static str,
+    en: &'static str,
+}
+
+// 所有命令行选项，增加或修改选项时同步修改这里
+static FLAGS: [FlagInfo; 35] = [
+    FlagInfo { usage: "--interactive", zh: "强制交互模式：读一行、显示调试信息后输出结果", en: "force interactive mode: read one line and show debug output" },
+    FlagInfo { usage: "--batch", zh: "强制批处理模式：逐行求值，每行输出一个结果", en: "force batch mode: evaluate each line and print one result per line" },
+    FlagInfo { usage: "--lang zh|en", zh: "提示信息的语言，默认取 CALC_LANG", en: "message language, defaults to CALC_LANG" },
+    FlagInfo { usage: "--help", zh: "输出用法和运算符列表", en: "print usage and the operator list" },
+    FlagInfo { usage: "--time", zh: "输出求值耗时", en: "print evaluation time" },
+    FlagInfo { usage: "--trace", zh: "输出结构化的求值跟踪", en: "print a structured evaluation trace" },
+    FlagInfo { usage: "--profile", zh: "输出 Token、语法规则和运算次数的统计", en: "print token, rule and operation counts" },
+    FlagInfo { usage: "--color", zh: "错误信息强制使用颜色", en: "always color error messages" },
+    FlagInfo { usage: "--no-color", zh: "错误信息不使用颜色", en: "never color error messages" },
+    FlagInfo { usage: "--no-fullwidth", zh: "不把全角字符转换成半角", en: "do not convert full-width characters" },
+    FlagInfo { usage: "--strict", zh: "两个数字之间缺少运算符时报错", en: "reject two numbers without an operator between them" },
+    FlagInfo { usage: "--strict-int", zh: "拒绝小数点和科学计数法", en: "reject decimal points and scientific notation" },
+    FlagInfo { usage: "--percent", zh: "'%' 表示左边结果的百分之几", en: "'%' takes a percentage of the left operand" },
+    FlagInfo { usage: "--thousands", zh: "数字中的逗号是千位分隔符", en: "commas in numbers are thousands separators" },
+    FlagInfo { usage: "--units h|m|s", zh: "数字可以带 h、m、s 时间单位，换算到所给单位", en: "numbers may carry h, m, s time units, converted to the given unit" },
+    FlagInfo { usage: "--width 8|16|32|64", zh: "整数宽度，默认 64", en: "integer width, default 64" },
+    FlagInfo { usage: "--unsigned", zh: "按无符号整数计算，范围是 0 到 2^宽度 - 1", en: "unsigned arithmetic, range 0 to 2^width - 1" },
+    FlagInfo { usage: "--overflow checked|wrap", zh: "溢出时报错还是回绕", en: "report or wrap on overflow" },
+    FlagInfo { usage: "--rounding MODE", zh: "'/' 的取整方式：trunc、floor、nearest、ceil、half-even", en: "rounding for '/': trunc, floor, nearest, ceil, half-even" },
+    FlagInfo { usage: "--round-half-even", zh: "同 --rounding half-even", en: "same as --rounding half-even" },
+    FlagInfo { usage: "--div-zero error|saturate", zh: "除数为 0 时报错还是取最大值或最小值", en: "report or saturate on division by zero" },
+    FlagInfo { usage: "--warn-truncation", zh: "整数除法丢弃余数时提示", en: "note when integer division drops a remainder" },
+    FlagInfo { usage: "--output-base 2|8|10|16|auto", zh: "结果的输出进制", en: "output base of the result" },
+    FlagInfo { usage: "--output-base-of-input", zh: "同 --output-base auto", en: "same as --output-base auto" },
+    FlagInfo { usage: "--group-base", zh: "非十进制结果用 '_' 分组", en: "group non-decimal output with '_'" },
+    FlagInfo { usage: "--locale NAME", zh: "十进制结果按地区习惯分组，如 de-DE", en: "group decimal output per locale, e.g. de-DE" },
+    FlagInfo { usage: "--max-ops N", zh: "运算次数上限", en: "limit on the number of operations" },
+    FlagInfo { usage: "--max-depth N", zh: "嵌套层数上限 (括号、函数调用、连续的负号和 **)，默认 256，最大 10000", en: "nesting limit for brackets, calls, repeated minus and **, default 256, at most 10000" },
+    FlagInfo { usage: "--max-ident-len N", zh: "标识符的最大长度，默认 64", en: "maximum identifier length, default 64" },
+    FlagInfo { usage: "--sandbox", zh: "禁用 divmod、tobase、table 等有输出的函数", en: "disable functions with output such as divmod, tobase, table" },
+    FlagInfo { usage: "--explain-error", zh: "报错后再给一行修改建议", en: "print a fix hint after an error" },
+    FlagInfo { usage: "--assert", zh: "结果为 0 的比较行算作断言失败，最后以非零状态退出", en: "comparison lines that yield 0 fail; exit non-zero at the end" },
+    FlagInfo { usage: "--implicit-ans", zh: "以二元运算符开头的行接着上一行的结果算", en: "lines starting with a binary operator continue from the previous result" },
+    FlagInfo { usage: "--reduce sum|product", zh: "之后的每个参数单独求值，再把结果相加或相乘", en: "evaluate each following argument and add or multiply the results" },
+    FlagInfo { usage: "-- EXPR...", zh: "之后的参数拼成一个算式求值", en: "evaluate the remaining arguments as one expression" },
+];
+
+// 用法说明、选项列表、运算符列表和支持的功能，选项部分由 FLAGS 生成，运算符部分由 operators() 生成，其余由 capabilities() 生成
+fn print_help(lang: Lang) {
+    match lang {
+        Lang::Zh => println!("用法: calc [选项] [-- 算式]\n选项:"),
+        Lang::En => println!("usage: calc [options] [-- expression]\noptions:"),
+    }
+    let usage_width = FLAGS.iter().map(|flag| flag.usage.len()).max().unwrap_or(0);
+    for flag in &FLAGS {
+        let description = match lang {
+            Lang::Zh => flag.zh,
+            Lang::En => flag.en,
+        };
+        println!("  {:<width$}  {}", flag.usage, description, width = usage_width);
+    }
+
+    match lang {
+        Lang::Zh => println!("运算符 (优先级从低到高):"),
+        Lang::En => println!("operators (lowest precedence first):"),
+    }
+    for op in operators() {
+        let assoc = match (op.assoc, lang) {
+            (Assoc::Left, Lang::Zh) => "左结合",
+            (Assoc::Right, Lang::Zh) => "右结合",
+            (Assoc::Chain, Lang::Zh) => "可连写",
+            (Assoc::Prefix, Lang::Zh) => "前缀",
+            (Assoc::Postfix, Lang::Zh) => "后缀",
+            (Assoc::Left, Lang::En) => "left",
+            (Assoc::Right, Lang::En) => "right",
+            (Assoc::Chain, Lang::En) => "chained",
+            (Assoc::Prefix, Lang::En) => "prefix",
+            (Assoc::Postfix, Lang::En) => "postfix",
+        };
+        // 按显示宽度对齐，中文占两列
+        let padding = 8usize.saturating_sub(assoc.chars().map(char_width).sum());
+        println!("  {:<4} {}  {}{} {}", op.symbol, op.precedence, assoc, " ".repeat(padding), op.description(lang));
+    }
+
+    let caps = capabilities();
+    let kinds: Vec<&str> = [
+        (caps.int, "int"), (caps.float, "float"), (caps.rational, "rational"), (caps.bigint, "bigint"),
+    ]
+    .iter()
+    .filter(|&&(enabled, _)| enabled)
+    .map(|&(_, name)| name)
+    .collect();
+    let widths: Vec<String> = caps.widths.iter().map(|w| w.to_string()).collect();
+    let labels = match lang {
+        Lang::Zh => ["数值类型", "函数", "--width", "--rounding", "--overflow", "--output-base"],
+        Lang::En => ["number types", "functions", "--width", "--rounding", "--overflow", "--output-base"],
+    };
+    let values = [
+        kinds.join(", "),
+        caps.functions.join(", "),
+        widths.join(", "),
+        caps.rounding_modes.join(", "),
+        caps.overflow_modes.join(", "),
+        caps.output_bases.join(", "),
+    ];
+    for (label, value) in labels.iter().zip(values.iter()) {
+        println!("{}: {}", label, value);
+    }
+}
+
+fn main() {
+    // 计算器选项的默认值都取自 CalculatorBuilder::new()
+    let defaults = CalculatorBuilder::new();
+
+    // 语言先看环境变量 CALC_LANG，命令行的 --lang 优先
+    let mut lang = env::var("CALC_LANG").ok().and_then(|name| Lang::parse(&name)).unwrap_or(defaults.lang);
+
+    // 解析命令行参数
+    let mut fullwidth = defaults.fullwidth;
+    let mut show_time = false;
+    let mut warn_truncation = defaults.warn_truncation;
+    let mut max_ops = defaults.max_ops;
+    let mut interactive = None; // None 表示自动判断
+    let mut strict = defaults.strict;
+    let mut show_trace = false;
+    let mut show_profile = false;
+    let mut percent = defaults.percent;
+    let mut units = defaults.units;
+    let mut thousands = defaults.thousands;
+    let mut sandbox = defaults.sandbox;
+    let mut group_base = defaults.group_base;
+    let mut strict_int = defaults.strict_int;
+    let mut max_ident_len = defaults.max_ident_len;
+    let mut depth_limit = defaults.depth_limit;
+    let mut unsigned = defaults.unsigned;
+    let mut explain_error = defaults.explain_error;
+    let mut div_zero = defaults.div_zero;
+    let mut assert = false;
+    let mut reduce = None; // 合并用的运算符：ADD 或 MUL
+    let mut reduce_args = Vec::new(); // --reduce 之后的算式参数
+    let mut implicit_ans = defaults.implicit_ans;
+    let mut locale_separator = defaults.locale_separator;
+    let mut show_help = false;
+    let mut color = None; // None 表示自动判断
+    let mut rounding = defaults.rounding;
+    let mut width = defaults.width;
+    let mut overflow = defaults.overflow;
+    let mut output_base = defaults.output_base;
+    let mut command_line_args: Option<Vec<String>> = None; // `--` 之后的参数
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-fullwidth" => fullwidth = false, // 关闭全角转半角
+            "--time" => show_time = true,          // 输出求值耗时
+            "--warn-truncation" => warn_truncation = true, // 整数除法截断时提示
+            "--max-ops" => {
+                // 运算次数上限
+                max_ops = match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) => Some(n),
+                    None => usage_error(lang, match lang {
+                        Lang::Zh => "--max-ops 需要一个非负整数参数",
+                        Lang::En => "--max-ops expects a non-negative integer",
+                    }),
+                };
+            },
+            "--max-ident-len" => {
+                // 标识符的最大长度，至少为 1
+                max_ident_len = match args.next().and_then(|n| n.parse::<usize>().ok()).filter(|&n| n >= 1) {
+                    Some(n) => n,
+                    None => usage_error(lang, match lang {
+                        Lang::Zh => "--max-ident-len 需要一个正整数参数",
+                        Lang::En => "--max-ident-len expects a positive integer",
+                    }),
+                };
+            },
+            "--max-depth" => {
+                // 最大嵌套层数，1 到 MAX_DEPTH_LIMIT
+                depth_limit = match args.next().and_then(|n| n.parse::<usize>().ok()).filter(|&n| n >= 1) {
+                    Some(n) if n <= MAX_DEPTH_LIMIT => n,
+                    Some(_) => usage_error(lang, &match lang {
+                        Lang::Zh => format!("--max-depth 不能超过 {}", MAX_DEPTH_LIMIT),
+                        Lang::En => format!("--max-depth must not exceed {}", MAX_DEPTH_LIMIT),
+                    }),
+                    None => usage_error(lang, match lang {
+                        Lang::Zh => "--max-depth 需要一个正整数参数",
+                        Lang::En => "--max-depth expects a positive integer",
+                    }),
+                };
+            },
+            "--reduce" => {
+                // 之后的每个参数单独求值，再把结果相加或相乘
+                reduce = match args.next().as_deref() {
+                    Some("sum") => Some(TokenType::ADD),
+                    Some("product") => Some(TokenType::MUL),
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--reduce 只支持 sum 或 product",
+                        Lang::En => "--reduce expects sum or product",
+                    }),
+                };
+            },
+            "--locale" => {
+                // 十进制结果按地区习惯分组，例如 de-DE 下 1000000 输出 1.000.000
+                let name = args.next().unwrap_or_default();
+                locale_separator = match LOCALES.iter().find(|&&(locale, _)| locale == name) {
+                    Some(&(_, separator)) => Some(separator),
+                    None => {
+                        let supported: Vec<&str> = LOCALES.iter().map(|&(locale, _)| locale).collect();
+                        usage_error(lang, &match lang {
+                            Lang::Zh => format!("不支持的地区 '{}'，可选: {}", name, supported.join(", ")),
+                            Lang::En => format!("unsupported locale '{}', expected one of: {}", name, supported.join(", ")),
+                        })
+                    },
+                };
+            },
+            "--implicit-ans" => implicit_ans = true,     // 以运算符开头的行接着上一行的结果算
+            "--assert" => assert = true,                 // 结果为 0 的比较行算作断言失败
+            "--explain-error" => explain_error = true,   // 报错后再给一行修改建议
+            "--unsigned" => unsigned = true,             // 无符号整数，范围是 0 到 2^宽度 - 1
+            "--strict" => strict = true,                 // 严格模式
+            "--percent" => percent = true,               // '%' 表示左边结果的百分之几
+            "--thousands" => thousands = true,           // 数字中的逗号是千位分隔符
+            "--strict-int" => strict_int = true,         // 拒绝 3.5、1e3 这样的写法
+            "--sandbox" => sandbox = true,               // 禁用 divmod、tobase 等有副作用的函数
+            "--units" => {
+                // 时间单位换算到的基本单位：h、m 或 s
+                units = match args.next().as_deref() {
+                    Some("h") => Some(3600),
+                    Some("m") => Some(60),
+                    Some("s") => Some(1),
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--units 只支持 h、m 或 s",
+                        Lang::En => "--units expects h, m or s",
+                    }),
+                };
+            },
+            "--trace" => show_trace = true,              // 输出结构化的求值跟踪
+            "--color" => color = Some(true),             // 错误信息强制使用颜色
+            "--no-color" => color = Some(false),         // 错误信息不使用颜色
+            "--help" => show_help = true,                // 输出用法和运算符列表
+            "--profile" => show_profile = true,          // 输出 Token、语法规则和运算次数的统计
+            "--interactive" => interactive = Some(true), // 强制交互模式
+            "--batch" => interactive = Some(false),      // 强制批处理模式
+            "--rounding" => {
+                // '/' 的取整方式：trunc、floor、nearest、ceil 或 half-even
+                rounding = match args.next().and_then(|name| RoundingMode::parse(&name)) {
+                    Some(mode) => mode,
+                    None => usage_error(lang, match lang {
+                        Lang::Zh => "--rounding 只支持 trunc、floor、nearest、ceil 或 half-even",
+                        Lang::En => "--rounding expects trunc, floor, nearest, ceil or half-even",
+                    }),
+                };
+            },
+            "--round-half-even" => rounding = RoundingMode::HalfEven, // 同 --rounding half-even
+            "--width" => {
+                // 整数宽度：8、16、32 或 64
+                width = match args.next().and_then(|n| n.parse::<u32>().ok()) {
+                    Some(n @ (8 | 16 | 32 | 64)) => n,
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--width 只支持 8、16、32 或 64",
+                        Lang::En => "--width expects 8, 16, 32 or 64",
+                    }),
+                };
+            },
+            "--overflow" => {
+                // 溢出处理：checked 报错，wrap 回绕
+                overflow = match args.next().as_deref() {
+                    Some("checked") => OverflowMode::Checked,
+                    Some("wrap") => OverflowMode::Wrapping,
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--overflow 只支持 checked 或 wrap",
+                        Lang::En => "--overflow expects checked or wrap",
+                    }),
+                };
+            },
+            "--div-zero" => {
+                // 除数为 0：error 报错，saturate 取最大值或最小值
+                div_zero = match args.next().as_deref() {
+                    Some("error") => DivZeroPolicy::Error,
+                    Some("saturate") => DivZeroPolicy::Saturate,
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--div-zero 只支持 error 或 saturate",
+                        Lang::En => "--div-zero expects error or saturate",
+                    }),
+                };
+            },
+            "--output-base" => {
+                // 输出进制：2、8、10、16 或 auto
+                output_base = match args.next().as_deref() {
+                    Some("auto") => OutputBase::Auto,
+                    Some(n @ ("2" | "8" | "10" | "16")) => OutputBase::Fixed(n.parse().unwrap()),
+                    _ => usage_error(lang, match lang {
+                        Lang::Zh => "--output-base 只支持 2、8、10、16 或 auto",
+                        Lang::En => "--output-base expects 2, 8, 10, 16 or auto",
+                    }),
+                };
+            },
+            "--group-base" => group_base = true, // 二进制、八进制、十六进制结果用 '_' 分组
+            "--output-base-of-input" => output_base = OutputBase::Auto, // 同 --output-base auto
+            "--lang" => {
+                // 界面语言：zh 或 en
+                lang = match args.next().and_then(|name| Lang::parse(&name)) {
+                    Some(l) => l,
+                    None => usage_error(lang, match lang {
+                        Lang::Zh => "--lang 只支持 zh 或 en",
+                        Lang::En => "--lang expects zh or en",
+                    }),
+                };
+            },
+            "--" => {
+                // 之后的参数都是算式，即使以 '-' 开头，例如 calc -- -3 + 4
+                command_line_args = Some(args.by_ref().collect());
+            },
+            // --reduce 之后不以 -- 开头的参数都是要合并的算式；以 '-' 开头的算式也可以写在 `--` 之后
+            _ if reduce.is_some() && !arg.starts_with("--") => reduce_args.push(arg),
+            _ => usage_error(lang, &match lang {
+                Lang::Zh => format!("未知参数 {}", arg),
+                Lang::En => format!("unknown argument {}", arg),
+            }),
+        }
+    }
+
+    if show_help {
+        print_help(lang);
+        return;
+    }
+
+    // 没有指定时，stderr 是终端并且没有设置 NO_COLOR 才使用颜色
+    let color = color.unwrap_or_else(|| {
+        io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+    });
+
+    // 没有指定模式时，stdin 是终端就用交互模式，否则（管道、重定向）用批处理模式
+    let interactive = interactive.unwrap_or_else(|| io::stdin().is_terminal());
+
+    let builder = CalculatorBuilder::new()
+        .fullwidth(fullwidth)
+        .lang(lang)
+        .strict(strict)
+        .warn_truncation(warn_truncation)
+        .rounding(rounding)
+        .width(width)
+        .overflow(overflow)
+        .output_base(output_base)
+        .max_ops(max_ops)
+        .percent(percent)
+        .units(units)
+        .thousands(thousands)
+        .sandbox(sandbox)
+        .strict_int(strict_int)
+        .max_ident_len(max_ident_len)
+        .depth_limit(depth_limit)
+        .unsigned(unsigned)
+        .group_base(group_base)
+        .explain_error(explain_error)
+        .div_zero(div_zero)
+        .implicit_ans(implicit_ans)
+        .locale_separator(locale_separator);
+
+    // 对一行算式求值，计时范围包括词法分析、语法分析和求值；出错时报告错误并退出
+    // history 保存之前各行的结果，求值完成后把本行结果追加进去
+    // variables 是之前各行用 => 保存的变量，本行保存的变量也会写回去
+    // 返回求值用的 Calculator，调用方据此格式化结果、判断断言
+    let run_line = |src: String, debug: bool, history: &mut Vec<i128>, variables: &mut HashMap<String, i128>| {
+        let start_time = Instant::now();
+
+        let mut calculator = builder.debug(debug).build(src);
+        calculator.history = mem::take(history);
+        calculator.variables = mem::take(variables);
+        // 在单独的线程上求值，栈大小按 --max-depth 预留，嵌套到上限也不会栈溢出
+        let result = thread::scope(|scope| {
+            thread::Builder::new()
+                .stack_size(STACK_BASE + depth_limit * STACK_PER_LEVEL)
+                .spawn_scoped(scope, || calculator.evaluate_checked())
+                .unwrap()
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload))
+        });
+        let elapsed = start_time.elapsed();
+
+        for line in &calculator.output {
+            match line {
+                Output::Stdout(text) => println!("{}", text),
+                Output::Stderr(text) => eprintln!("{}", text),
+            }
+        }
+        let expr_val = match result {
+            Ok(v) => v,
+            Err(error) => {
+                calculator.report(&error, color);
+                process::exit(1);
+            },
+        };
+
+        if show_trace {
+            // 每行一条：规则名 @位置 = 值，写到 stderr
+            for event in &calculator.trace {
+                match event.value {
+                    Some(v) => eprintln!("{:?} @{} = {}", event.rule, event.position, v),
+                    None => eprintln!("{:?} @{}", event.rule, event.position),
+                }
+            }
+        }
+
+        if show_profile {
+            // 统计写到 stderr：Token 个数、最大嵌套深度 (--max-depth 计数的层数)、最大语法规则深度、运算次数，
+            // 以及每条语法规则进入的次数
+            let rule_counts: Vec<String> = Rule::ALL
+                .iter()
+                .map(|&rule| (rule, calculator.trace.iter().filter(|e| e.rule == rule).count()))
+                .filter(|&(_, count)| count > 0)
+                .map(|(rule, count)| format!("{:?} {}", rule, count))
+                .collect();
+            match lang {
+                Lang::Zh => eprintln!("(统计) Token: {}，最大嵌套深度: {}，最大规则深度: {}，运算: {}",
+                    calculator.token_count, calculator.max_nesting, calculator.max_depth, calculator.op_count),
+                Lang::En => eprintln!("(profile) tokens: {}, max nesting depth: {}, max rule depth: {}, operations: {}",
+                    calculator.token_count, calculator.max_nesting, calculator.max_depth, calculator.op_count),
+            }
+            eprintln!("  {}", rule_counts.join(", "));
+        }
+
+        *history = mem::take(&mut calculator.history);
+        *variables = mem::take(&mut calculator.variables);
+        history.push(expr_val);
+        (calculator, expr_val, elapsed)
+    };
+
+    // 求值一行并按输出设置格式化结果
+    let eval_line = |src: String, debug: bool, history: &mut Vec<i128>, variables: &mut HashMap<String, i128>| {
+        let (calculator, expr_val, elapsed) = run_line(src, debug, history, variables);
+        // 比较结果为 0 就是断言失败，不是比较的行不算断言
+        let passed = !calculator.comparison || expr_val != 0;
+        (calculator.format_value(expr_val), elapsed, passed)
+    };
+
+    // 断言失败写到 stderr，批处理模式下带上行号 (从 1 开始，空行也算)
+    let report_failed_assertion = |number: Option<usize>, src: &str| {
+        let src = src.trim();
+        match (lang, number) {
+            (Lang::Zh, Some(n)) => eprintln!("断言失败: 第 {} 行: {}", n, src),
+            (Lang::Zh, None) => eprintln!("断言失败: {}", src),
+            (Lang::En, Some(n)) => eprintln!("Assertion failed: line {}: {}", n, src),
+            (Lang::En, None) => eprintln!("Assertion failed: {}", src),
+        }
+    };
+
+    // 耗时写到 stderr，不影响 stdout 上的结果
+    let report_time = |elapsed: Duration| {
+        if show_time {
+            match lang {
+                Lang::Zh => eprintln!("(耗时 {:?})", elapsed),
+                Lang::En => eprintln!("(took {:?})", elapsed),
+            }
+        }
+    };
+
+    let mut history = Vec::new();
+    let mut variables = HashMap::new();
+
+    if let Some(op) = reduce {
+        // 每个参数各自独立求值，互相看不到对方的 @、$N 和变量；结果再逐个相加或相乘，
+        // 每一步都按当前宽度和溢出方式约束，溢出时指出是第几个参数
+        let exprs: Vec<String> = reduce_args.into_iter().chain(command_line_args.unwrap_or_default()).collect();
+        if exprs.is_empty() {
+            usage_error(lang, match lang {
+                Lang::Zh => "--reduce 需要给出至少一个算式",
+                Lang::En => "--reduce expects at least one expression",
+            });
+        }
+
+        // 输出进制为 auto 时，看所有参数中出现过的字面量进制
+        let mut combined = builder.build(String::new());
+        let mut total: Option<i128> = None;
+        let mut elapsed = Duration::ZERO;
+        for (number, src) in exprs.iter().enumerate() {
+            let (calculator, value, took) = run_line(clean_input(src), false, &mut Vec::new(), &mut HashMap::new());
+            combined.literal_bases.extend(&calculator.literal_bases);
+            elapsed += took;
+            let result = match total {
+                None => Ok(value),
+                Some(acc) if op == TokenType::ADD => combined.fit(acc + value, 0),
+                Some(acc) => combined.multiply(acc, value, 0),
+            };
+            total = match result {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    calculator.report(&EvalError { index: 0, err: CalcError::ReduceOverflow(number + 1) }, color);
+                    process::exit(1);
+                },
+            };
+        }
+        println!("{}", combined.format_value(total.unwrap()));
+        report_time(elapsed);
+        return;
+    }
+
+    // 算式的来源依次是：`--` 之后的参数、环境变量 CALC_EXPR、stdin
+    let command_line_expr = command_line_args.map(|args| args.join(" "));
+    let single_expr = command_line_expr.or_else(|| env::var("CALC_EXPR").ok());
+
+    if let Some(src) = single_expr {
+        // 命令行或 CALC_EXPR 给出了算式：只求值这一条，不读 stdin
+        let (output, elapsed, passed) = eval_line(clean_input(&src), false, &mut history, &mut variables);
+        println!("{}", output);
+        report_time(elapsed);
+        if assert && !passed {
+            report_failed_assertion(None, &src);
+            process::exit(1);
+        }
+    } else if interactive {
+        print!("{}", lang.prompt());
+        // 确保提示立即显示
+        io::stdout().flush().unwrap();
+
+        let mut buf = String::new();
+        io::stdin().read_line(&mut buf).unwrap();
+
+        // 交互模式下启用 调试 模式
+        let (output, elapsed, _) = eval_line(clean_input(&buf), true, &mut history, &mut variables);
+        println!("{}: {}", lang.result_label(), output);
+        report_time(elapsed);
+    } else {
+        // 批处理模式：不打印提示，逐行求值，每行输出一个结果，空行跳过
+        // --assert 下断言失败不中断，全部算完之后再以非零状态退出
+        let mut failed = 0;
+        for (number, line) in io::stdin().lock().lines().enumerate() {
+            let line = line.unwrap();
+            let src = clean_input(&line);
+            if src.is_empty() {
+                continue;
+            }
+
+            let (output, elapsed, passed) = eval_line(src, false, &mut history, &mut variables);
+            println!("{}", output);
+            report_time(elapsed);
+            if assert && !passed {
+                report_failed_assertion(Some(number + 1), &line);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            process::exit(1);
+        }
+    }
+}